@@ -0,0 +1,151 @@
+//! Bidirectional path tracing (Veach and Guibas 1995). Each sample traces one
+//! subpath out from a light and one from the camera through the pixel, then
+//! joins every prefix of the one to every prefix of the other. Each of those
+//! (s, t) connections is a separate estimate of the same integral, so they
+//! are blended with the power heuristic according to how likely each
+//! strategy was to find the path. Connections that go straight from the
+//! light subpath to the camera (t = 1) land on whatever pixel they hit.
+
+use nalgebra::Vector3;
+use rand::Rng;
+
+use crate::bsdf::Bsdf;
+use crate::camera::ImageBuffer;
+use crate::mlt::{Path, Vertex};
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vector::Ray;
+
+/// Trace `n` pairs of subpaths, with the camera ones starting in `pixel`, and
+/// record every connection between them
+pub fn draw<R: Rng + ?Sized>(
+    pixel: usize,
+    n: usize,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let camera = &scene.camera;
+    let c = settings.continue_chance;
+    // camera subpaths only cover this pixel, but connections to the camera
+    // are spread over the whole film, which works out to the same scale
+    let scale = 1. / (settings.film_area() * n as f64);
+    for _ in 0..n {
+        let light = match scene.sample_light(rng) {
+            Some(light) => light,
+            None => return,
+        };
+        let (start, normal, emitter) = light;
+        let dir = emitter.propose(normal, rng).1;
+        let light_side = subpath(scene, start, dir, c, rng);
+        let dir = camera.sample_pixel(pixel, settings, rng);
+        let camera_side = subpath(scene, camera.pos, dir, c, rng);
+
+        // s and t count the surface points taken from each subpath
+        for s in 0..=light_side.len() {
+            for t in 0..=camera_side.len() {
+                if s + t == 0 {
+                    // the lights themselves are drawn separately
+                    continue;
+                }
+                let path = Path::connect(light, &light_side[..s], &camera_side[..t], camera);
+
+                let density = path.split_pdf(0, path.last(), s, settings)
+                    * roulette(s, t, c)
+                    * scene.light_pdf(emitter, normal);
+                if density > 0. {
+                    let weight = mis_weight(&path, s, c, settings) * scale / density;
+                    camera.record_sample(&path, scene, image, settings, weight);
+                }
+            }
+        }
+    }
+}
+
+/// Trace out from `start` in direction `dir`, scattering off each surface
+/// that is hit and going on each time with `continue_chance`. The first ray
+/// is always traced.
+pub fn subpath<'a, R: Rng + ?Sized>(
+    scene: &'a Scene,
+    mut start: Vector3<f64>,
+    mut dir: Vector3<f64>,
+    continue_chance: f64,
+    rng: &mut R,
+) -> Vec<Vertex<'a>> {
+    let mut vertices = vec![];
+    loop {
+        let ray = Ray::new(start, dir);
+        let (t, normal, object, _) = match scene.cast(ray) {
+            Some(hit) => hit,
+            None => break,
+        };
+        let point = ray.of(t);
+        vertices.push((point, normal, object));
+        if !rng.gen_bool(continue_chance) {
+            break;
+        }
+        start = point;
+        dir = object.material.at(normal).sample(-dir, rng).1;
+    }
+    vertices
+}
+
+/// Chance of a light subpath getting as far as `s` surface points and a
+/// camera subpath as far as `t`, when each goes on with `continue_chance`
+/// after its first point
+pub fn roulette(s: usize, t: usize, continue_chance: f64) -> f64 {
+    continue_chance.powi(s.max(1) as i32 - 1) * continue_chance.powi(t.max(1) as i32 - 1)
+}
+
+/// Power heuristic weight of taking the first `split` surface points of
+/// `path` from the light subpath, against every other way of splitting it.
+/// The subpaths went on with `continue_chance` after their first point, or 1
+/// if they were walked out to a set length.
+pub fn mis_weight(
+    path: &Path,
+    split: usize,
+    continue_chance: f64,
+    settings: &RenderSettings,
+) -> f64 {
+    let last = path.last();
+    let pdf = |split| {
+        let density = path.split_pdf(0, last, split, settings)
+            * roulette(split, last - 1 - split, continue_chance);
+        density.powi(2)
+    };
+    let total: f64 = (0..last).map(pdf).sum();
+    pdf(split) / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::mlt;
+    use crate::scene::corner;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let scene = corner(Material::Specular(Color::new(0.5, 0.5, 0.5), 5.));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            bootstrap_samples: 400_000,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+}
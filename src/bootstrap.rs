@@ -0,0 +1,95 @@
+//! Getting the Markov chains started. A chain only ever samples paths in
+//! proportion to its target function, so on its own it can't say how bright
+//! the image is. Before any chain runs, a batch of independent samples
+//! estimates the target's integral b over all paths, and the chains start
+//! from seeds resampled out of the same batch in proportion to their weight,
+//! which puts them in the stationary distribution from the first step.
+
+use rand::Rng;
+
+pub struct Bootstrap<T> {
+    /// Estimate of the target function's integral over all paths
+    pub b: f64,
+    seeds: Vec<T>,
+    /// Running total of the seeds' weights
+    cdf: Vec<f64>,
+}
+
+impl<T> Bootstrap<T> {
+    /// Draw `count` independent samples, each of which is a seed and its
+    /// weight: the target function divided by the sample's density. A sample
+    /// of `None` counts as having no weight.
+    pub fn new<F: FnMut() -> Option<(f64, T)>>(count: usize, mut sample: F) -> Self {
+        let mut seeds = vec![];
+        let mut cdf = vec![];
+        let b = estimate_b(count, || {
+            let (weight, seed) = sample()?;
+            if weight > 0. && weight.is_finite() {
+                seeds.push(seed);
+                cdf.push(cdf.last().unwrap_or(&0.) + weight);
+            }
+            Some(weight)
+        });
+        Bootstrap { b, seeds, cdf }
+    }
+    /// Pick a seed with probability proportional to its weight, or `None` if
+    /// no sample found any light at all
+    pub fn seed<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<&T> {
+        let total = *self.cdf.last()?;
+        let u = rng.gen_range(0. ..total);
+        let i = self.cdf.partition_point(|&c| c <= u);
+        self.seeds.get(i.min(self.seeds.len() - 1))
+    }
+}
+
+/// Just the estimate of b from `count` independent samples of the target
+/// function divided by the sample's density, for when there are no chains to
+/// seed. Samples of `None`, or that aren't positive and finite, count as zero.
+pub fn estimate_b<F: FnMut() -> Option<f64>>(count: usize, mut sample: F) -> f64 {
+    let total: f64 = (0..count)
+        .filter_map(|_| sample())
+        .filter(|weight| *weight > 0. && weight.is_finite())
+        .sum();
+    total / count.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn resampling() {
+        let weights = [0., 1., f64::NAN, 3.];
+        let mut i = 0;
+        let bootstrap = Bootstrap::new(4, || {
+            i += 1;
+            Some((weights[i - 1], i - 1))
+        });
+        assert_abs_diff_eq!(bootstrap.b, 1.);
+        let mut i = 0;
+        let b = estimate_b(4, || {
+            i += 1;
+            Some(weights[i - 1])
+        });
+        assert_abs_diff_eq!(b, 1.);
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut counts = [0; 4];
+        for _ in 0..10000 {
+            counts[*bootstrap.seed(rng).unwrap()] += 1;
+        }
+        assert_eq!(counts[0] + counts[2], 0);
+        assert!(
+            (counts[3] as f64 / 10000. - 0.75).abs() < 0.03,
+            "{:?}",
+            counts
+        );
+
+        let empty = Bootstrap::new(10, || None::<(f64, ())>);
+        assert_eq!(empty.b, 0.);
+        assert!(empty.seed(rng).is_none());
+    }
+}
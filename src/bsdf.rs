@@ -0,0 +1,278 @@
+//! Scattering at surfaces, worked out in each surface's local shading frame,
+//! where the normal is +z. Directions point away from the surface: `wi`
+//! towards where light arrives from, and `wo` towards where it leaves to.
+
+use nalgebra::Vector3;
+use rand::Rng;
+
+use crate::color::Color;
+use crate::vector::Frame;
+
+/// How far below 1 the cosine between two directions can be for them to
+/// count as the same, for specular surfaces
+const SPECULAR_TOLERANCE: f64 = 1e-9;
+
+pub trait Bsdf {
+    /// The fraction of light arriving from `wi` that leaves towards `wo`,
+    /// per unit solid angle (and projected area) on each side
+    fn eval(&self, wi: Vector3<f64>, wo: Vector3<f64>) -> Color;
+    /// Pick a direction for light leaving towards `wo` to have arrived from,
+    /// giving its density per unit solid angle along with it. Densities of
+    /// directions picked the other way round, for light leaving towards
+    /// them, work out the same, so this does too.
+    fn sample<R: Rng + ?Sized>(&self, wo: Vector3<f64>, rng: &mut R) -> (f64, Vector3<f64>);
+    /// Density per unit solid angle of `sample` picking `wi` for `wo`
+    fn pdf(&self, wi: Vector3<f64>, wo: Vector3<f64>) -> f64;
+    /// The BSDF at a point with surface normal `normal`, working in world
+    /// space
+    fn at(&self, normal: Vector3<f64>) -> Shading<'_, Self>
+    where
+        Self: Sized,
+    {
+        Shading {
+            frame: Frame::new(normal),
+            bsdf: self,
+        }
+    }
+}
+
+/// The mirror reflection of `w` about the normal
+pub fn mirror(w: Vector3<f64>) -> Vector3<f64> {
+    Vector3::new(-w[0], -w[1], w[2])
+}
+
+/// The fraction of unpolarized light arriving at cosine `cos_in` to the
+/// normal that a dielectric reflects, going into a medium `eta` times as
+/// dense as the one it came from. All of it, past the critical angle.
+pub fn fresnel(cos_in: f64, eta: f64) -> f64 {
+    let sin2_out = (1. - cos_in * cos_in) / (eta * eta);
+    if sin2_out >= 1. {
+        return 1.;
+    }
+    let cos_out = (1. - sin2_out).sqrt();
+    let s = (cos_in - eta * cos_out) / (cos_in + eta * cos_out);
+    let p = (eta * cos_in - cos_out) / (eta * cos_in + cos_out);
+    (s * s + p * p) / 2.
+}
+
+/// The fraction of unpolarized light arriving at cosine `cos_in` to the
+/// normal that a conductor reflects, for each channel of its complex index
+/// of refraction `eta` + i `k`
+pub fn fresnel_conductor(cos_in: f64, eta: Color, k: Color) -> Color {
+    let cos2 = cos_in * cos_in;
+    let sin2 = 1. - cos2;
+    let channel = |eta: f64, k: f64| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4. * eta * eta * k * k).sqrt();
+        let a = ((a2_plus_b2 + t0) / 2.).max(0.).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2. * cos_in * a;
+        let s = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let p = s * (t3 - t4) / (t3 + t4);
+        (s + p) / 2.
+    };
+    Color::new(
+        channel(eta.r, k.r),
+        channel(eta.g, k.g),
+        channel(eta.b, k.b),
+    )
+}
+
+/// Whether the unit vectors `a` and `b` point the same way, give or take
+/// rounding: close enough to be the one direction a specular surface sends
+/// light along
+pub fn along(a: Vector3<f64>, b: Vector3<f64>) -> bool {
+    a.dot(&b) > 1. - SPECULAR_TOLERANCE
+}
+
+/// A BSDF at a point on a surface, taking and giving directions in world
+/// space rather than the surface's local frame
+#[derive(Debug, Clone, Copy)]
+pub struct Shading<'a, B> {
+    frame: Frame,
+    bsdf: &'a B,
+}
+
+impl<B: Bsdf> Shading<'_, B> {
+    pub fn eval(&self, incoming: Vector3<f64>, outgoing: Vector3<f64>) -> Color {
+        self.bsdf.eval(self.local(incoming), self.local(outgoing))
+    }
+    pub fn sample<R: Rng + ?Sized>(
+        &self,
+        outgoing: Vector3<f64>,
+        rng: &mut R,
+    ) -> (f64, Vector3<f64>) {
+        let (pdf, wi) = self.bsdf.sample(self.local(outgoing), rng);
+        (pdf, self.frame.to_world(wi))
+    }
+    pub fn pdf(&self, incoming: Vector3<f64>, outgoing: Vector3<f64>) -> f64 {
+        self.bsdf.pdf(self.local(incoming), self.local(outgoing))
+    }
+    fn local(&self, dir: Vector3<f64>) -> Vector3<f64> {
+        self.frame.to_local(dir.normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::microfacet::Distribution;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn white_surfaces_keep_all_the_light() {
+        let white = Color::new(1., 1., 1.);
+        let materials = [
+            Material::Diffuse(white),
+            Material::Combined(vec![
+                (0.5, Material::Diffuse(white)),
+                (0.5, Material::Specular(white, 1.)),
+            ]),
+            // a metal that reflects almost everything
+            Material::Conductor {
+                distribution: Distribution::Ggx,
+                roughness: 0.2,
+                eta: Color::new(0.01, 0.01, 0.01),
+                k: Color::new(10., 10., 10.),
+            },
+        ];
+        let rng = &mut StdRng::seed_from_u64(1);
+        // including a normal pointing straight down, where the frame has to
+        // be built differently
+        for normal in [Vector3::new(0.3, 1., -0.2), -Vector3::z()] {
+            let normal = normal.normalize();
+            let outgoing = normal + Vector3::new(0.1, 0.2, 0.3);
+            for material in &materials {
+                let shading = material.at(normal);
+                let n = 100_000;
+                let total: f64 = (0..n)
+                    .map(|_| {
+                        let (pdf, incoming) = shading.sample(outgoing, rng);
+                        assert!((shading.pdf(incoming, outgoing) - pdf).abs() < 1e-9);
+                        let cos = incoming.dot(&normal).max(0.);
+                        shading.eval(incoming, outgoing).luminance() * cos / pdf
+                    })
+                    .sum::<f64>()
+                    / n as f64;
+                // a Phong lobe near the horizon loses a little off the edge
+                assert!(total <= 1.02 && total > 0.9, "{:?}: {}", material, total);
+            }
+        }
+    }
+
+    #[test]
+    fn glass_reflects_and_refracts_by_fresnel() {
+        // 4% of light is reflected head on, going either way
+        assert!((fresnel(1., 1.5) - 0.04).abs() < 1e-9);
+        assert!((fresnel(1., 1. / 1.5) - 0.04).abs() < 1e-9);
+
+        let glass = Material::Dielectric {
+            ior: 1.5,
+            tint: Color::new(1., 1., 1.),
+        };
+        let rng = &mut StdRng::seed_from_u64(1);
+        // from inside, past the critical angle, everything is reflected
+        let inside = Vector3::new(0.9, 0., -0.3).normalize();
+        for _ in 0..100 {
+            let (pdf, wi) = glass.sample(inside, rng);
+            assert!((pdf - inside[2].abs() * 1.5 * 1.5).abs() < 1e-9);
+            assert!(along(wi, mirror(inside)));
+        }
+
+        // from outside, the two directions are picked as often as they carry
+        // light, so the estimate is exactly the light that gets through
+        let outside = Vector3::new(0.5, 0.2, 0.8).normalize();
+        let reflected = fresnel(outside[2], 1.5);
+        let n = 10_000;
+        let mut refractions = 0;
+        for _ in 0..n {
+            let (pdf, wi) = glass.sample(outside, rng);
+            assert!((glass.pdf(wi, outside) - pdf).abs() < 1e-9);
+            let weight = glass.eval(wi, outside).luminance() * wi[2].abs() / pdf;
+            if wi[2] < 0. {
+                refractions += 1;
+                // radiance from inside spreads out over a wider cone
+                assert!((weight - 1. / (1.5 * 1.5)).abs() < 1e-9);
+            } else {
+                assert!((weight - 1.).abs() < 1e-9);
+            }
+        }
+        let fraction = refractions as f64 / n as f64;
+        assert!((fraction - (1. - reflected)).abs() < 0.02, "{}", fraction);
+        // and nothing goes anywhere else
+        assert_eq!(glass.eval(Vector3::z(), outside).luminance(), 0.);
+        assert_eq!(glass.pdf(Vector3::z(), outside), 0.);
+    }
+
+    #[test]
+    fn rough_glass_keeps_the_light() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        for distribution in [Distribution::Ggx, Distribution::Beckmann] {
+            let glass = Material::RoughDielectric {
+                distribution,
+                roughness: 0.2,
+                ior: 1.5,
+                tint: Color::new(1., 1., 1.),
+            };
+            for wo in [
+                Vector3::new(0.3, 0.2, 0.9).normalize(),
+                Vector3::new(0.3, 0.2, -0.9).normalize(),
+            ] {
+                let n = 100_000;
+                let total: f64 = (0..n)
+                    .map(|_| {
+                        let (pdf, wi) = glass.sample(wo, rng);
+                        assert!((glass.pdf(wi, wo) - pdf).abs() < 1e-9 * pdf.max(1.));
+                        if pdf == 0. {
+                            return 0.;
+                        }
+                        let weight = glass.eval(wi, wo).luminance() * wi[2].abs() / pdf;
+                        // undo radiance spreading out or squeezing together
+                        // as it crosses into the other side
+                        if wi[2] * wo[2] < 0. {
+                            weight
+                                * if wo[2] > 0. {
+                                    1.5 * 1.5
+                                } else {
+                                    1. / (1.5 * 1.5)
+                                }
+                        } else {
+                            weight
+                        }
+                    })
+                    .sum::<f64>()
+                    / n as f64;
+                // facets shadowing each other lose a little
+                assert!(
+                    total <= 1.01 && total > 0.9,
+                    "{:?}: {}",
+                    distribution,
+                    total
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn phong_lobe_peaks_at_the_mirror_direction() {
+        let material = Material::Specular(Color::new(1., 1., 1.), 20.);
+        let shading = material.at(Vector3::y());
+        let outgoing = Vector3::new(1., 1., 0.);
+        let mirror = shading
+            .eval(Vector3::new(-1., 1., 0.), outgoing)
+            .luminance();
+        for off in [Vector3::new(-1., 1.2, 0.), Vector3::new(-1., 1., 0.2)] {
+            assert!(shading.eval(off, outgoing).luminance() < mirror);
+        }
+        // the same angle off the mirror direction either way gives the same
+        let left = shading.eval(Vector3::new(-1., 1., 0.3), outgoing);
+        let right = shading.eval(Vector3::new(-1., 1., -0.3), outgoing);
+        assert!((left.luminance() - right.luminance()).abs() < 1e-9);
+        // and nothing comes from behind the surface
+        assert_eq!(shading.eval(-Vector3::y(), outgoing).luminance(), 0.);
+    }
+}
@@ -0,0 +1,415 @@
+//! Bounding volume hierarchy over every primitive in the scene.
+//!
+//! Meshes are split up so that each triangle is its own primitive, and the
+//! tree is built with the surface area heuristic. Nodes are stored flattened
+//! in depth-first order, so the first child of an interior node is always the
+//! node right after it. Unbounded shapes like planes can't go in the tree and
+//! are tested separately on every ray.
+
+use nalgebra::Vector3;
+
+use crate::scene::Object;
+use crate::vector::Ray;
+
+/// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f64>,
+    pub max: Vector3<f64>,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vector3::repeat(f64::INFINITY),
+            max: Vector3::repeat(f64::NEG_INFINITY),
+        }
+    }
+    pub fn around<'a, I: IntoIterator<Item = &'a Vector3<f64>>>(points: I) -> Self {
+        points
+            .into_iter()
+            .fold(Aabb::empty(), |b, p| b.union(&Aabb { min: *p, max: *p }))
+    }
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+    pub fn centroid(&self) -> Vector3<f64> {
+        (self.min + self.max) / 2.
+    }
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.min() < 0. {
+            0.
+        } else {
+            2. * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+        }
+    }
+    /// Slab test, returning whether the ray passes through the box somewhere
+    /// between its start and `t_max`.
+    fn hit(&self, ray: &Ray<f64>, inv_dir: &Vector3<f64>, t_max: f64) -> bool {
+        let mut t0 = 0.;
+        let mut t1 = t_max;
+        for i in 0..3 {
+            let mut near = (self.min[i] - ray.start[i]) * inv_dir[i];
+            let mut far = (self.max[i] - ray.start[i]) * inv_dir[i];
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaN (from 0 * infinity) never narrows the interval
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// One piece of an object: the whole shape, or a single triangle of a mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Primitive {
+    pub object: usize,
+    pub part: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf {
+        bounds: Aabb,
+        first: usize,
+        count: usize,
+    },
+    Interior {
+        bounds: Aabb,
+        /// index of the second child, the first one directly follows
+        second: usize,
+        axis: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    primitives: Vec<Primitive>,
+    unbounded: Vec<Primitive>,
+}
+
+const MAX_LEAF: usize = 4;
+const BUCKETS: usize = 12;
+/// Cost of visiting an interior node, relative to intersecting a primitive
+const TRAVERSAL_COST: f64 = 0.125;
+
+#[derive(Debug, Clone, Copy)]
+struct BuildInfo {
+    primitive: Primitive,
+    bounds: Aabb,
+    centroid: Vector3<f64>,
+}
+
+impl Bvh {
+    pub fn new(objects: &[Object]) -> Self {
+        let mut infos = vec![];
+        let mut unbounded = vec![];
+        for (object, o) in objects.iter().enumerate() {
+            for part in 0..o.shape.parts() {
+                let primitive = Primitive { object, part };
+                match o.shape.bounds(part) {
+                    Some(bounds) => infos.push(BuildInfo {
+                        primitive,
+                        bounds,
+                        centroid: bounds.centroid(),
+                    }),
+                    None => unbounded.push(primitive),
+                }
+            }
+        }
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * infos.len()),
+            primitives: Vec::with_capacity(infos.len()),
+            unbounded,
+        };
+        if !infos.is_empty() {
+            bvh.build(&mut infos);
+        }
+        bvh
+    }
+    /// Build the subtree over `infos`, returning the index of its root
+    fn build(&mut self, infos: &mut [BuildInfo]) -> usize {
+        let bounds = infos
+            .iter()
+            .fold(Aabb::empty(), |b, info| b.union(&info.bounds));
+        let index = self.nodes.len();
+        let centroids = Aabb::around(infos.iter().map(|info| &info.centroid));
+        let axis = (centroids.max - centroids.min).imax();
+        let extent = centroids.max[axis] - centroids.min[axis];
+        if infos.len() <= 1 || extent <= 0. {
+            return self.leaf(bounds, infos);
+        }
+
+        // sort the centroids into buckets along the widest axis, and find the
+        // cheapest place to split between them
+        let bucket = |info: &BuildInfo| {
+            let b =
+                (BUCKETS as f64 * (info.centroid[axis] - centroids.min[axis]) / extent) as usize;
+            b.min(BUCKETS - 1)
+        };
+        let mut counts = [0; BUCKETS];
+        let mut boxes = [Aabb::empty(); BUCKETS];
+        for info in infos.iter() {
+            let b = bucket(info);
+            counts[b] += 1;
+            boxes[b] = boxes[b].union(&info.bounds);
+        }
+        let (mut split, mut cost) = (0, f64::INFINITY);
+        for candidate in 1..BUCKETS {
+            let side = |range: std::ops::Range<usize>| {
+                range.fold((0, Aabb::empty()), |(n, b), i| {
+                    (n + counts[i], b.union(&boxes[i]))
+                })
+            };
+            let (n0, b0) = side(0..candidate);
+            let (n1, b1) = side(candidate..BUCKETS);
+            let c = TRAVERSAL_COST
+                + (n0 as f64 * b0.surface_area() + n1 as f64 * b1.surface_area())
+                    / bounds.surface_area();
+            if c < cost {
+                split = candidate;
+                cost = c;
+            }
+        }
+        if infos.len() <= MAX_LEAF && cost >= infos.len() as f64 {
+            return self.leaf(bounds, infos);
+        }
+        let mid = partition(infos, |info| bucket(info) < split);
+        if mid == 0 || mid == infos.len() {
+            return self.leaf(bounds, infos);
+        }
+
+        self.nodes.push(Node::Interior {
+            bounds,
+            second: 0,
+            axis,
+        });
+        let (first, rest) = infos.split_at_mut(mid);
+        self.build(first);
+        let second_index = self.build(rest);
+        if let Node::Interior { second, .. } = &mut self.nodes[index] {
+            *second = second_index;
+        }
+        index
+    }
+    fn leaf(&mut self, bounds: Aabb, infos: &[BuildInfo]) -> usize {
+        self.nodes.push(Node::Leaf {
+            bounds,
+            first: self.primitives.len(),
+            count: infos.len(),
+        });
+        self.primitives
+            .extend(infos.iter().map(|info| info.primitive));
+        self.nodes.len() - 1
+    }
+    /// Visit every primitive whose leaf the ray passes through before `t_max`.
+    /// The visitor returns a new (smaller) `t_max`, or `None` to stop early.
+    fn traverse<F>(&self, ray: &Ray<f64>, mut t_max: f64, mut visit: F)
+    where
+        F: FnMut(Primitive, f64) -> Option<f64>,
+    {
+        for &primitive in &self.unbounded {
+            match visit(primitive, t_max) {
+                Some(t) => t_max = t,
+                None => return,
+            }
+        }
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = ray.dir.map(|d| 1. / d);
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(index) = stack.pop() {
+            match &self.nodes[index] {
+                Node::Leaf {
+                    bounds,
+                    first,
+                    count,
+                } => {
+                    if bounds.hit(ray, &inv_dir, t_max) {
+                        for &primitive in &self.primitives[*first..first + count] {
+                            match visit(primitive, t_max) {
+                                Some(t) => t_max = t,
+                                None => return,
+                            }
+                        }
+                    }
+                }
+                Node::Interior {
+                    bounds,
+                    second,
+                    axis,
+                } => {
+                    if bounds.hit(ray, &inv_dir, t_max) {
+                        // visit the nearer child first
+                        if ray.dir[*axis] < 0. {
+                            stack.push(index + 1);
+                            stack.push(*second);
+                        } else {
+                            stack.push(*second);
+                            stack.push(index + 1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// The closest hit along the ray, as the distance, surface normal and
+    /// index of the object hit. The normal faces back along the ray, unless
+    /// the object's material is sided, when it faces out the front.
+    pub fn cast(&self, ray: Ray<f64>, objects: &[Object]) -> Option<(f64, Vector3<f64>, usize)> {
+        let mut closest = None;
+        self.traverse(&ray, f64::INFINITY, |p, t_max| {
+            if let Some((t, normal, flipped)) = objects[p.object].shape.cast_part(p.part, ray) {
+                if t < t_max {
+                    let sided = flipped && objects[p.object].material.sided();
+                    let normal = if sided { -normal } else { normal };
+                    closest = Some((t, normal, p.object));
+                    return Some(t);
+                }
+            }
+            Some(t_max)
+        });
+        closest
+    }
+    /// Whether anything at all is hit before `t_max`
+    pub fn occluded(&self, ray: Ray<f64>, t_max: f64, objects: &[Object]) -> bool {
+        let mut hit = false;
+        self.traverse(&ray, t_max, |p, t_max| {
+            match objects[p.object].shape.cast_part(p.part, ray) {
+                Some((t, ..)) if t < t_max => {
+                    hit = true;
+                    None
+                }
+                _ => Some(t_max),
+            }
+        });
+        hit
+    }
+}
+
+/// Move everything matching `pred` to the front, returning how many did.
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::Rng;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::mesh::Mesh;
+    use crate::scene::Shape;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn random_objects<R: Rng>(rng: &mut R) -> Vec<Object> {
+        let mut point = || {
+            Vector3::new(
+                rng.gen_range(-5. ..5.),
+                rng.gen_range(-5. ..5.),
+                rng.gen_range(-5. ..5.),
+            )
+        };
+        let material = Material::Diffuse(Color::new(1., 1., 1.));
+        let mut objects = vec![];
+        for _ in 0..50 {
+            objects.push(Object {
+                shape: Shape::Sphere {
+                    center: point(),
+                    radius: 0.3,
+                },
+                material: material.clone(),
+            });
+        }
+        let positions: Vec<_> = (0..300).map(|_| point() / 2.).collect();
+        let triangles = (0..100).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+        objects.push(Object {
+            shape: Shape::Mesh(Arc::new(
+                Mesh::new(positions, vec![], vec![], triangles).unwrap(),
+            )),
+            material: material.clone(),
+        });
+        objects.push(Object {
+            shape: Shape::Plane {
+                center: Vector3::new(0., -6., 0.),
+                normal: Vector3::y(),
+            },
+            material,
+        });
+        objects
+    }
+
+    fn brute_force(ray: Ray<f64>, objects: &[Object]) -> Option<(f64, usize)> {
+        let mut closest: Option<(f64, usize)> = None;
+        for (i, o) in objects.iter().enumerate() {
+            if let Some((t, ..)) = o.shape.cast(ray) {
+                if !matches!(closest, Some((c, _)) if c <= t) {
+                    closest = Some((t, i));
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let objects = random_objects(rng);
+        let bvh = Bvh::new(&objects);
+        for _ in 0..2000 {
+            let start = Vector3::new(
+                rng.gen_range(-6. ..6.),
+                rng.gen_range(-6. ..6.),
+                rng.gen_range(-6. ..6.),
+            );
+            let dir = Vector3::new(
+                rng.gen_range(-1. ..1.),
+                rng.gen_range(-1. ..1.),
+                rng.gen_range(-1. ..1.),
+            );
+            let ray = Ray::new(start, dir);
+            let expected = brute_force(ray, &objects);
+            let found = bvh.cast(ray, &objects).map(|(t, _, i)| (t, i));
+            assert_eq!(found, expected);
+            assert_eq!(
+                bvh.occluded(ray, 1., &objects),
+                matches!(expected, Some((t, _)) if t < 1.)
+            );
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let bvh = Bvh::new(&[]);
+        let ray = Ray::new(Vector3::zeros(), Vector3::x());
+        assert!(bvh.cast(ray, &[]).is_none());
+        assert!(!bvh.occluded(ray, 1., &[]));
+    }
+}
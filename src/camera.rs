@@ -1,23 +1,24 @@
 use std::sync::Mutex;
-use std::usize;
 
 use nalgebra::{Rotation3, Vector3};
 use rand::Rng;
+use serde::Deserialize;
 
+use crate::bsdf::Bsdf;
 use crate::color::Color;
 use crate::mlt::Path;
 use crate::scene::Scene;
+use crate::settings::RenderSettings;
 use crate::vector::Ray;
-use crate::DISTANCE_FACTOR;
+use crate::MIN_DIST;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "CameraDesc")]
 pub struct Camera {
     pub pos: Vector3<f64>,
     rotation: Rotation3<f64>,
     /// focal length
     f: f64,
-    /// Angular distance to each edge of the lens
-    pub fov: f64,
 }
 
 impl Camera {
@@ -33,19 +34,54 @@ impl Camera {
             rotation: Rotation3::look_at_lh(&facing, &up),
             f: 1. / fov.tan(),
             pos,
-            fov,
         }
     }
-    pub fn record_sample(&self, path: &Path, scene: &Scene, image: &ImageBuffer, weight: f64) {
-        let point = self.rotation.transform_vector(
-            &(path.points[path.points.len() - 2] - path.points[path.points.len() - 1]),
-        );
-        let projected = self.f / point[2] * point;
-        let x = ((projected[0] + 1.) * image.height as f64 / 2.) as usize;
-        let y = ((-projected[1] + 1.) * image.height as f64 / 2.) as usize;
+    pub fn record_sample(
+        &self,
+        path: &Path,
+        scene: &Scene,
+        image: &ImageBuffer,
+        settings: &RenderSettings,
+        weight: f64,
+    ) {
+        if let Some((pixel, color)) = self.contribution(path, scene, settings) {
+            image.buffer.lock().unwrap()[pixel] += color * weight;
+        }
+    }
+    /// The pixel a path lands on, as an index into the image buffer, and the
+    /// light it carries there. `None` if it misses the image.
+    pub fn contribution(
+        &self,
+        path: &Path,
+        scene: &Scene,
+        settings: &RenderSettings,
+    ) -> Option<(usize, Color)> {
+        let camera_vertex = path.points[path.points.len() - 2];
+        let (x, y) = self.project(camera_vertex, settings)?;
+        // x spans -1..1 across the width, and y is scaled the same way
+        let (width, height) = (settings.width, settings.height);
+        let x = ((x + 1.) * width as f64 / 2.) as usize;
+        let y = ((height as f64 - y * width as f64) / 2.) as usize;
+        if x >= width || y >= height {
+            return None;
+        }
+        let point = camera_vertex - self.pos;
+        if scene.occluded(Ray::new(self.pos, point), 1. - MIN_DIST) {
+            return Some((width * y + x, Color::new(0., 0., 0.)));
+        }
 
-        let mut color =
-            path.light.color / (1. + DISTANCE_FACTOR * point.magnitude_squared()) * weight;
+        // importance of a pinhole camera, converting from the area around the
+        // vertex to the area of the film
+        let cos = self.rotation.transform_vector(&point).normalize()[2];
+        let importance = self.f * self.f / (cos.powi(3) * point.magnitude_squared());
+        let emitted = path
+            .light
+            .emitted(path.light_normal, path.points[1] - path.points[0]);
+        let mut color = emitted * importance;
+        if path.objects.is_empty() {
+            // the light shines straight into the camera
+            color *= path.light.attenuate(point.magnitude_squared());
+        }
 
         for i in 0..path.objects.len() {
             let x0 = path.points[i];
@@ -55,35 +91,90 @@ impl Camera {
             let outgoing = x2 - x1;
             let normal = path.normals[i];
             // check occlusion
-            if let Some((t, _, _)) = scene.cast(Ray::new(x1, incoming)) {
-                if t < 1. {
-                    color *= 0.;
-                    break;
-                }
+            // (stopping just short of x0, so its own surface doesn't count)
+            if scene.occluded(Ray::new(x1, incoming), 1. - MIN_DIST) {
+                color *= 0.;
+                break;
             }
-            let mut geom = 1. / (1. + DISTANCE_FACTOR * incoming.magnitude_squared());
-            geom *= incoming.normalize().dot(&normal);
+            // inverse square law, and both ends of every edge are foreshortened,
+            // so count the cosine on the way in and the way out
+            // (except for light from a distant light, which doesn't spread out)
+            let d2 = incoming.magnitude_squared();
+            let mut geom = match i {
+                0 if path.light.is_distant() => 1.,
+                0 => path.light.attenuate(d2) / d2,
+                _ => 1. / d2,
+            };
+            geom *= incoming.normalize().dot(&normal).abs();
+            geom *= outgoing.normalize().dot(&normal).abs();
             color *= geom;
 
             // BSDF contribution
-            let phi_in = incoming.angle(&normal);
-            let phi_out = outgoing.angle(&normal);
-            // project both onto the plane formed by the
-            // this means that only symmetric distributions are allowed, due to the way we measure
-            let proj_in = incoming - incoming.dot(&normal) / normal.magnitude_squared() * normal;
-            let proj_out = outgoing - outgoing.dot(&normal) / normal.magnitude_squared() * normal;
-            let theta = proj_in.angle(&proj_out);
-            color *= path.objects[i].material.bsdf(phi_in, theta, phi_out)
-        }
-        let mut buffer = image.buffer.lock().unwrap();
-        if image.width * y + x < buffer.len() {
-            buffer[image.width * y + x] += color;
+            color *= path.objects[i].material.at(normal).eval(incoming, outgoing)
         }
+        Some((width * y + x, color))
     }
     pub fn propose(&self, x: f64, y: f64) -> Vector3<f64> {
         let v = Vector3::new(x, y, self.f);
         self.rotation.inverse_transform_vector(&v)
     }
+    /// Pick a direction through a uniformly random point on the film
+    pub fn sample<R: Rng + ?Sized>(&self, settings: &RenderSettings, rng: &mut R) -> Vector3<f64> {
+        let aspect = settings.aspect();
+        self.propose(rng.gen_range(-1. ..1.), rng.gen_range(-aspect..aspect))
+    }
+    /// Pick a direction through a uniformly random point on the film inside
+    /// pixel number `pixel`
+    pub fn sample_pixel<R: Rng + ?Sized>(
+        &self,
+        pixel: usize,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Vector3<f64> {
+        let (width, height) = (settings.width as f64, settings.height as f64);
+        let x = (pixel % settings.width) as f64 + rng.gen::<f64>();
+        let y = (pixel / settings.width) as f64 + rng.gen::<f64>();
+        // the inverse of the mapping from the film to pixels in `contribution`
+        self.propose(2. * x / width - 1., (height - 2. * y) / width)
+    }
+    /// Density per unit solid angle of `sample` choosing the direction `dir`
+    pub fn pdf(&self, dir: Vector3<f64>, settings: &RenderSettings) -> f64 {
+        if self.project(self.pos + dir, settings).is_none() {
+            return 0.;
+        }
+        let cos = self.rotation.transform_vector(&dir).normalize()[2];
+        self.f * self.f / (settings.film_area() * cos.powi(3))
+    }
+    /// Where a point lands on the film, in the coordinates `propose` takes, or
+    /// `None` if it is behind the camera or outside the image.
+    pub fn project(&self, point: Vector3<f64>, settings: &RenderSettings) -> Option<(f64, f64)> {
+        let v = self.rotation.transform_vector(&(point - self.pos));
+        if v[2] <= 0. {
+            return None;
+        }
+        let (x, y) = (self.f * v[0] / v[2], self.f * v[1] / v[2]);
+        if x.abs() > 1. || y.abs() > settings.aspect() {
+            return None;
+        }
+        Some((x, y))
+    }
+}
+
+/// How a camera is written in a scene file. The field of view is given in
+/// degrees, since that is what most people are used to typing.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    pos: Vector3<f64>,
+    facing: Vector3<f64>,
+    up: Vector3<f64>,
+    fov: f64,
+}
+
+impl From<CameraDesc> for Camera {
+    fn from(desc: CameraDesc) -> Self {
+        Camera::new(desc.pos, desc.facing, desc.up, desc.fov.to_radians())
+    }
 }
 
 #[derive(Debug)]
@@ -92,3 +183,13 @@ pub struct ImageBuffer {
     pub width: usize,
     pub height: usize,
 }
+
+impl ImageBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        ImageBuffer {
+            buffer: Mutex::new(vec![Color::new(0., 0., 0.); width * height]),
+            width,
+            height,
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use std::iter::Sum;
 use std::ops::{Add, AddAssign, Div, Mul, MulAssign};
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(from = "[f64; 3]")]
 pub struct Color {
     pub r: f64,
     pub g: f64,
@@ -17,6 +20,11 @@ impl Color {
         0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
     }
 }
+impl From<[f64; 3]> for Color {
+    fn from([r, g, b]: [f64; 3]) -> Color {
+        Color { r, g, b }
+    }
+}
 impl From<Color> for u32 {
     fn from(color: Color) -> u32 {
         fn map(c: f64) -> u32 {
@@ -62,7 +70,7 @@ impl Mul for Color {
         Color {
             r: self.r * rhs.r,
             g: self.g * rhs.g,
-            b: self.b + rhs.b,
+            b: self.b * rhs.b,
         }
     }
 }
@@ -112,4 +120,10 @@ mod tests {
         let c2 = 0x00_ff_80_00;
         assert_eq!(c1, c2, "{:x} != {:x}", c1, c2);
     }
+
+    #[test]
+    fn multiply() {
+        let c = Color::new(1., 2., 3.) * Color::new(0.5, 0.25, 2.);
+        assert_eq!(c, Color::new(0.5, 0.5, 6.));
+    }
 }
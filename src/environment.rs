@@ -0,0 +1,199 @@
+//! Light from an environment map surrounding the scene, stored as an
+//! equirectangular image: the top row looks straight up, the bottom row
+//! straight down, and the columns go once around the horizon. Directions are
+//! picked by importance, in proportion to how bright the map is there,
+//! through the brightness of each row and then of each pixel along it.
+
+use std::f64::consts::{PI, TAU};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use nalgebra::Vector3;
+use rand::Rng;
+
+use crate::color::Color;
+use crate::hdr::{read_pfm, read_rgbe};
+
+#[derive(Debug, Clone)]
+pub struct Environment {
+    width: usize,
+    height: usize,
+    colors: Vec<Color>,
+    /// How far the map is turned around the vertical axis, in radians
+    rotation: f64,
+    /// The total weight of the rows up to and including each one
+    rows: Vec<f64>,
+    /// For each row, the total weight of its pixels up to and including
+    /// each one
+    columns: Vec<Vec<f64>>,
+    /// The luminance of the map integrated over every direction
+    power: f64,
+}
+
+impl Environment {
+    /// Load a map from a .hdr or .pfm file, turned by `rotation` radians
+    /// around the vertical axis
+    pub fn load(path: &Path, rotation: f64) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let (width, height, colors) = match path.extension().and_then(|e| e.to_str()) {
+            Some("hdr") => read_rgbe(file)?,
+            Some("pfm") => read_pfm(file)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "environment maps have to be .hdr or .pfm files",
+                ))
+            }
+        };
+        Ok(Environment::new(width, height, colors, rotation))
+    }
+    pub fn new(width: usize, height: usize, colors: Vec<Color>, rotation: f64) -> Self {
+        let mut rows = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(height);
+        let mut total = 0.;
+        let mut map = Environment {
+            width,
+            height,
+            colors,
+            rotation,
+            rows: vec![],
+            columns: vec![],
+            power: 0.,
+        };
+        for (y, row) in map.colors.chunks(width).enumerate() {
+            let solid_angle = map.solid_angle(y);
+            let mut row_total = 0.;
+            columns.push(
+                row.iter()
+                    .map(|c| {
+                        row_total += c.luminance().max(0.) * solid_angle;
+                        row_total
+                    })
+                    .collect(),
+            );
+            total += row_total;
+            rows.push(total);
+        }
+        map.rows = rows;
+        map.columns = columns;
+        map.power = total;
+        map
+    }
+    /// The light arriving from the direction `dir`
+    pub fn radiance(&self, dir: Vector3<f64>) -> Color {
+        let (x, y) = self.pixel(dir);
+        self.colors[y * self.width + x]
+    }
+    /// The luminance of the map integrated over every direction
+    pub fn power(&self) -> f64 {
+        self.power
+    }
+    /// Pick a direction towards the map, in proportion to its luminance
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        let pick = |totals: &Vec<f64>, u: f64| {
+            let target = u * totals.last().copied().unwrap_or(0.);
+            totals
+                .partition_point(|&total| total <= target)
+                .min(totals.len() - 1)
+        };
+        let y = pick(&self.rows, rng.gen());
+        let x = pick(&self.columns[y], rng.gen());
+        // uniform over the pixel's solid angle, by the hat-box theorem
+        let (top, bottom) = self.band(y);
+        let z = top + (bottom - top) * rng.gen::<f64>();
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = (x as f64 + rng.gen::<f64>()) / self.width as f64 * TAU + self.rotation;
+        Vector3::new(r * phi.cos(), z, r * phi.sin())
+    }
+    /// Density per unit solid angle of `sample` picking the direction `dir`
+    pub fn pdf(&self, dir: Vector3<f64>) -> f64 {
+        if self.power <= 0. {
+            return 0.;
+        }
+        // every direction in a pixel is as likely as any other
+        self.radiance(dir).luminance().max(0.) / self.power
+    }
+    /// The heights of the top and bottom of row `y`
+    fn band(&self, y: usize) -> (f64, f64) {
+        let height = |y: usize| (y as f64 / self.height as f64 * PI).cos();
+        (height(y), height(y + 1))
+    }
+    /// The solid angle each pixel in row `y` covers
+    fn solid_angle(&self, y: usize) -> f64 {
+        let (top, bottom) = self.band(y);
+        (top - bottom) * TAU / self.width as f64
+    }
+    /// The pixel the direction `dir` looks at
+    fn pixel(&self, dir: Vector3<f64>) -> (usize, usize) {
+        let dir = dir.normalize();
+        let theta = dir[1].clamp(-1., 1.).acos();
+        let phi = (dir[2].atan2(dir[0]) - self.rotation).rem_euclid(TAU);
+        let x = (phi / TAU * self.width as f64) as usize;
+        let y = (theta / PI * self.height as f64) as usize;
+        (x.min(self.width - 1), y.min(self.height - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn sampling_matches_pdf() {
+        // a dim sky over a bright ground, with one brighter patch
+        let mut colors = vec![Color::new(0.2, 0.2, 0.2); 8 * 4];
+        for c in &mut colors[16..] {
+            *c = Color::new(1., 1., 1.);
+        }
+        colors[27] = Color::new(8., 8., 8.);
+        let map = Environment::new(8, 4, colors, 1.);
+        let rng = &mut StdRng::seed_from_u64(1);
+
+        // samples should land in each pixel as often as the pdf says
+        let n = 100_000;
+        let mut counts = [0; 8 * 4];
+        for _ in 0..n {
+            let (x, y) = map.pixel(map.sample(rng));
+            counts[y * 8 + x] += 1;
+        }
+        for y in 0..4 {
+            let solid_angle = map.solid_angle(y);
+            for x in 0..8 {
+                let expected = map.colors[y * 8 + x].luminance() * solid_angle / map.power();
+                let fraction = counts[y * 8 + x] as f64 / n as f64;
+                assert!(
+                    (fraction - expected).abs() < 0.01,
+                    "{} vs {}",
+                    fraction,
+                    expected
+                );
+            }
+        }
+
+        // and the pdf should integrate to 1, with uniform directions
+        let n = 400_000;
+        let total: f64 = (0..n)
+            .map(|_| {
+                let z: f64 = rng.gen_range(-1. ..1.);
+                let phi = rng.gen_range(0. ..TAU);
+                let r = (1. - z * z).sqrt();
+                map.pdf(Vector3::new(r * phi.cos(), z, r * phi.sin())) * 4. * PI
+            })
+            .sum::<f64>()
+            / n as f64;
+        assert!((total - 1.).abs() < 0.03, "{}", total);
+
+        // the bright patch is where it should be, turned by the rotation
+        let phi = 3.5 / 8. * TAU + 1.;
+        let theta = 3.5 / 4. * PI;
+        let dir = Vector3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        );
+        assert_eq!(map.radiance(dir), Color::new(8., 8., 8.));
+    }
+}
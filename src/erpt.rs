@@ -0,0 +1,107 @@
+//! Energy redistribution path tracing (Cline, Talbot and Egbert 2005). Each
+//! pixel samples its own paths as usual, but rather than recording a path's
+//! light where it lands, the path starts a few short Metropolis chains that
+//! spread its light over nearby paths. Every chain step deposits the same
+//! fixed amount of light, so the number of chains started is the path's
+//! light divided by what one chain deposits, rounded up or down at random.
+//! Chains then start in proportion to the target function, which is the
+//! stationary distribution, so the image comes out unbiased while keeping
+//! each pixel's own share of the samples.
+
+use rand::Rng;
+
+use crate::camera::ImageBuffer;
+use crate::mlt::step;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+
+/// Sample `n` paths through `pixel` and redistribute each one's light. `b` is
+/// the integral of the target function over all paths, which sets how much
+/// light each step deposits so that there is about one chain per path.
+pub fn draw<R: Rng + ?Sized>(
+    pixel: usize,
+    n: usize,
+    b: f64,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let mutations = settings.chain_length;
+    let scale = 1. / (settings.film_area() * n as f64);
+    let deposit = b * scale / mutations as f64;
+    if deposit <= 0. {
+        return;
+    }
+    for _ in 0..n {
+        let (density, path) = match scene.propose_in(Some(pixel), settings, rng) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let value = path.measure(scene, settings);
+        let energy = value * scale / density;
+        if !(energy > 0. && energy.is_finite()) {
+            continue;
+        }
+        let chains = (energy / (deposit * mutations as f64) + rng.gen::<f64>()) as usize;
+        for _ in 0..chains {
+            let (mut path, mut value) = (path.clone(), value);
+            for _ in 0..mutations {
+                scene
+                    .camera
+                    .record_sample(&path, scene, image, settings, deposit / value);
+                let (next, next_value) = step(path, value, scene, settings, rng);
+                path = next;
+                value = next_value;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::mlt;
+    use crate::pathtracer;
+    use crate::scene::corner;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn matches_path_tracer() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let scene = corner(Material::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            chain_length: 5,
+            ..Default::default()
+        };
+        let pixels = settings.width * settings.height;
+        let total = |image: &ImageBuffer| {
+            let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+            total.luminance()
+        };
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        let b = mlt::estimate_b(&scene, &settings, rng);
+        for pixel in 0..pixels {
+            draw(pixel, 128, b, &scene, &image, &settings, rng);
+        }
+        let erpt = total(&image);
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..pixels {
+            pathtracer::draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let reference = total(&image);
+        assert!(
+            (erpt - reference).abs() < 0.05 * reference,
+            "{} vs {}",
+            erpt,
+            reference
+        );
+    }
+}
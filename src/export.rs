@@ -0,0 +1,186 @@
+//! Saving the rendered image to disk.
+//!
+//! The image buffer holds linear radiance, so it has to go through the sRGB
+//! transfer function before being quantized to 8 bits. Without that the
+//! midtones come out far too dark. The HDR formats in [`crate::hdr`] skip all
+//! of that and store the buffer as is.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rand::Rng;
+
+use crate::camera::ImageBuffer;
+use crate::color::Color;
+use crate::hdr;
+use crate::settings::SaveOptions;
+
+/// The sRGB opto-electronic transfer function, taking a linear value in 0..1
+/// to an encoded value in 0..1.
+pub fn srgb_encode(c: f64) -> f64 {
+    let c = c.clamp(0., 1.);
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Encode a linear channel value as an 8-bit sRGB value. `offset` is where
+/// in the 0..1 range between two levels to round up, 0.5 for plain rounding.
+fn quantize(c: f64, offset: f64) -> u8 {
+    (srgb_encode(c) * 255. + offset).floor().min(255.) as u8
+}
+
+/// Convert linear colors into packed 8-bit sRGB triples, brightened by
+/// `exposure` stops first. With dithering, the rounding point is picked at
+/// random so that smooth gradients don't band.
+pub fn to_rgb8(colors: &[Color], exposure: f64, dither: bool) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut out = Vec::with_capacity(colors.len() * 3);
+    for &c in colors {
+        let c = c * exposure.exp2();
+        for &v in &[c.r, c.g, c.b] {
+            let offset = if dither { rng.gen_range(0. ..1.) } else { 0.5 };
+            out.push(quantize(v, offset));
+        }
+    }
+    out
+}
+
+/// Convert linear colors into the `0RGB` format the preview window expects,
+/// brightened by `exposure` stops.
+pub fn to_preview(colors: &[Color], exposure: f64, buffer: &mut [u32]) {
+    for (&c, out) in colors.iter().zip(buffer) {
+        let c = c * exposure.exp2();
+        let r = quantize(c.r, 0.5) as u32;
+        let g = quantize(c.g, 0.5) as u32;
+        let b = quantize(c.b, 0.5) as u32;
+        *out = r << 16 | g << 8 | b;
+    }
+}
+
+/// Save the image, choosing the format from the file extension.
+pub fn save<P: AsRef<Path>>(image: &ImageBuffer, path: P, options: &SaveOptions) -> io::Result<()> {
+    let path = path.as_ref();
+    let colors = image.buffer.lock().unwrap().clone();
+    let (width, height) = (image.width, image.height);
+    let out = || File::create(path).map(BufWriter::new);
+    let rgb8 = || to_rgb8(&colors, options.exposure, options.dither);
+    match extension(path).as_deref() {
+        Some("png") => write_png(out()?, width, height, &rgb8()),
+        Some("ppm") => write_ppm(out()?, width, height, &rgb8()),
+        Some("hdr") => hdr::write_rgbe(out()?, width, height, &colors),
+        Some("pfm") => hdr::write_pfm(out()?, width, height, &colors),
+        Some("exr") => hdr::write_exr(out()?, width, height, &colors, options.half),
+        _ => check_format(path),
+    }
+}
+
+/// Fail unless `save` knows how to write an image to `path`, so that a bad
+/// extension can be caught before spending any time on rendering.
+pub fn check_format(path: &Path) -> io::Result<()> {
+    match extension(path).as_deref() {
+        Some("png") | Some("ppm") | Some("hdr") | Some("pfm") | Some("exr") => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported image format: {}", path.display()),
+        )),
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase)
+}
+
+/// Binary PPM: a short text header followed by raw RGB bytes.
+pub fn write_ppm<W: Write>(mut out: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    out.write_all(rgb)?;
+    out.flush()
+}
+
+pub fn write_png<W: Write>(out: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    // mark the data as sRGB, with perceptual rendering intent
+    writer.write_chunk(*b"sRGB", &[0])?;
+    writer.write_image_data(rgb)?;
+    Ok(())
+}
+
+/// Where to save the `n`th snapshot taken while rendering to `output`, e.g.
+/// `render.png` becomes `render-3.png`.
+pub fn snapshot_path(output: Option<&Path>, n: usize) -> PathBuf {
+    let output = output.unwrap_or_else(|| Path::new("snapshot.png"));
+    let stem = output
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .unwrap_or("snapshot");
+    let ext = extension(output).unwrap_or_else(|| "png".to_owned());
+    output.with_file_name(format!("{}-{}.{}", stem, n, ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::*;
+
+    #[test]
+    fn srgb() {
+        assert_eq!(srgb_encode(0.), 0.);
+        assert_abs_diff_eq!(srgb_encode(1.), 1., epsilon = 1e-12);
+        // linear 18% grey is a little under half brightness once encoded
+        assert_abs_diff_eq!(srgb_encode(0.18), 0.4614, epsilon = 1e-4);
+        assert_eq!(srgb_encode(2.), srgb_encode(1.));
+        assert_eq!(srgb_encode(-1.), 0.);
+    }
+
+    #[test]
+    fn rgb8() {
+        let colors = [Color::new(0., 0.5, 1.), Color::new(10., -1., 0.0002)];
+        assert_eq!(to_rgb8(&colors, 0., false), vec![0, 188, 255, 255, 0, 1]);
+        // a stop brighter is twice the light
+        assert_eq!(
+            to_rgb8(&colors, 1., false),
+            to_rgb8(&[colors[0] * 2., colors[1] * 2.], 0., false)
+        );
+        assert_eq!(
+            to_rgb8(&colors, -1., false)[1],
+            to_rgb8(&[Color::new(0., 0.25, 0.)], 0., false)[1]
+        );
+        let dithered = to_rgb8(&colors, 0., true);
+        assert!(dithered[1] == 187 || dithered[1] == 188);
+        assert_eq!(dithered[2], 255);
+    }
+
+    #[test]
+    fn ppm_header() {
+        let mut out = vec![];
+        write_ppm(&mut out, 2, 1, &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+
+    #[test]
+    fn formats() {
+        assert!(check_format(Path::new("render.PNG")).is_ok());
+        assert!(check_format(Path::new("render.jpg")).is_err());
+        assert!(check_format(Path::new("render")).is_err());
+    }
+
+    #[test]
+    fn snapshots() {
+        assert_eq!(
+            snapshot_path(Some(Path::new("out/render.ppm")), 2),
+            Path::new("out/render-2.ppm")
+        );
+        assert_eq!(snapshot_path(None, 1), Path::new("snapshot-1.png"));
+    }
+}
@@ -0,0 +1,437 @@
+//! High dynamic range image formats. These store the accumulated radiance
+//! exactly as it sits in the image buffer, without clamping or gamma, so
+//! renders can be compared numerically or tone mapped elsewhere. Radiance
+//! HDR and PFM files can be read back in too, for environment maps.
+
+use std::io::{self, BufRead, Write};
+
+use crate::color::Color;
+
+/// Radiance RGBE (.hdr), written uncompressed. Each pixel shares one 8-bit
+/// exponent between its three 8-bit mantissas.
+pub fn write_rgbe<W: Write>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    colors: &[Color],
+) -> io::Result<()> {
+    write!(
+        out,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    for &c in colors {
+        out.write_all(&to_rgbe(c))?;
+    }
+    out.flush()
+}
+
+fn to_rgbe(c: Color) -> [u8; 4] {
+    let (r, g, b) = (c.r.max(0.), c.g.max(0.), c.b.max(0.));
+    let v = r.max(g).max(b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f64.powi(e) >= 1. {
+        e += 1;
+    }
+    let e = e.clamp(-128, 127);
+    let scale = 256. / 2f64.powi(e);
+    let m = |x: f64| (x * scale).min(255.) as u8;
+    [m(r), m(g), m(b), (e + 128) as u8]
+}
+
+/// Portable float map (.pfm): a text header, then little-endian 32-bit floats
+/// with the bottom row first.
+pub fn write_pfm<W: Write>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    colors: &[Color],
+) -> io::Result<()> {
+    // a negative scale marks the data as little-endian
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in colors.chunks(width).rev() {
+        for c in row {
+            for &v in &[c.r, c.g, c.b] {
+                out.write_all(&(v as f32).to_le_bytes())?;
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Uncompressed scanline OpenEXR (.exr), with channels stored either as
+/// 16-bit halfs or 32-bit floats.
+pub fn write_exr<W: Write>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    colors: &[Color],
+    half: bool,
+) -> io::Result<()> {
+    // magic number, then version 2 with no flags set (single part scanline)
+    out.write_all(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0])?;
+
+    let mut header = vec![];
+    let (pixel_type, size) = if half { (1i32, 2) } else { (2i32, 4) };
+    let mut channels = vec![];
+    // channels have to be listed in alphabetical order
+    for name in &[b"B", b"G", b"R"] {
+        channels.extend_from_slice(*name);
+        channels.push(0);
+        channels.extend_from_slice(&pixel_type.to_le_bytes());
+        // linear flag and reserved bytes, then x and y sampling
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut header, "channels", "chlist", &channels);
+    attribute(&mut header, "compression", "compression", &[0]);
+    let mut window = vec![];
+    for &v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // increasing y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+    out.write_all(&header)?;
+
+    // one scanline per block, each block being its y coordinate, its size,
+    // and then every channel of the line in turn
+    let line_size = width * 3 * size;
+    let block_size = 8 + line_size;
+    let table_start = 8 + header.len();
+    let first_block = table_start + 8 * height;
+    for y in 0..height {
+        out.write_all(&((first_block + y * block_size) as u64).to_le_bytes())?;
+    }
+    let mut line = Vec::with_capacity(line_size);
+    for (y, row) in colors.chunks(width).enumerate() {
+        line.clear();
+        for channel in &[|c: &Color| c.b, |c: &Color| c.g, |c: &Color| c.r] {
+            for c in row {
+                let v = channel(c) as f32;
+                if half {
+                    line.extend_from_slice(&to_half(v).to_le_bytes());
+                } else {
+                    line.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line_size as i32).to_le_bytes())?;
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Convert to an IEEE 754 half-precision float, rounding to nearest even.
+fn to_half(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // infinity stays infinity, and NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (half, shift, rest) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // subnormal, so the implicit leading bit becomes explicit
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        (mantissa >> shift, shift, mantissa & ((1 << shift) - 1))
+    } else {
+        (((e as u32) << 10) | (mantissa >> 13), 13, mantissa & 0x1fff)
+    };
+    let halfway = 1 << (shift - 1);
+    // a carry out of the mantissa correctly bumps the exponent
+    let round = (rest > halfway || (rest == halfway && half & 1 == 1)) as u32;
+    sign | (half + round) as u16
+}
+
+/// An image read back in: its width, its height, and its pixels row by row
+/// from the top
+pub type Image = (usize, usize, Vec<Color>);
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Read a Radiance RGBE (.hdr) file, either flat or run length encoded, in
+/// the usual top to bottom, left to right orientation
+pub fn read_rgbe<R: BufRead>(mut input: R) -> io::Result<Image> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file"));
+    }
+    // header lines run up to a blank one, and the resolution comes after it
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(invalid("missing resolution"));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid("only RGB HDR files are supported"));
+            }
+        }
+    }
+    line.clear();
+    input.read_line(&mut line)?;
+    let (height, width) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (height.parse(), width.parse()),
+        _ => return Err(invalid("only -Y +X orientation is supported")),
+    };
+    let (height, width): (usize, usize) = match (height, width) {
+        (Ok(height), Ok(width)) => (height, width),
+        _ => return Err(invalid("invalid resolution")),
+    };
+
+    let mut colors = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        let mut start = [0; 4];
+        input.read_exact(&mut start)?;
+        let encoded = (8..0x8000).contains(&width)
+            && start[..2] == [2, 2]
+            && ((start[2] as usize) << 8 | start[3] as usize) == width;
+        if encoded {
+            // each channel is stored separately, as runs of one repeated
+            // byte or of bytes given one by one
+            for channel in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let mut count = [0; 1];
+                    input.read_exact(&mut count)?;
+                    let (count, run) = match count[0] {
+                        c if c > 128 => (c as usize - 128, true),
+                        c => (c as usize, false),
+                    };
+                    if count == 0 || x + count > width {
+                        return Err(invalid("bad run length"));
+                    }
+                    let mut byte = [0; 1];
+                    if run {
+                        input.read_exact(&mut byte)?;
+                        for pixel in &mut scanline[x..x + count] {
+                            pixel[channel] = byte[0];
+                        }
+                    } else {
+                        for pixel in &mut scanline[x..x + count] {
+                            input.read_exact(&mut byte)?;
+                            pixel[channel] = byte[0];
+                        }
+                    }
+                    x += count;
+                }
+            }
+        } else {
+            scanline[0] = start;
+            for pixel in &mut scanline[1..] {
+                input.read_exact(pixel)?;
+            }
+        }
+        colors.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+    }
+    Ok((width, height, colors))
+}
+
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Color {
+    if e == 0 {
+        return Color::new(0., 0., 0.);
+    }
+    // the middle of the range each mantissa was rounded down from
+    let scale = 2f64.powi(e as i32 - 136);
+    let m = |x: u8| (x as f64 + 0.5) * scale;
+    Color::new(m(r), m(g), m(b))
+}
+
+/// Read a portable float map (.pfm), in color or grayscale and either byte
+/// order
+pub fn read_pfm<R: BufRead>(mut input: R) -> io::Result<Image> {
+    let mut words = vec![];
+    // the header is three whitespace separated words after the magic number
+    while words.len() < 4 {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated header"));
+        }
+        words.extend(line.split_whitespace().map(str::to_owned));
+    }
+    let channels = match words[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a PFM file")),
+    };
+    let parse = |word: &str| word.parse::<f64>().map_err(|_| invalid("invalid header"));
+    let (width, height, scale) = (parse(&words[1])?, parse(&words[2])?, parse(&words[3])?);
+    let (width, height) = (width as usize, height as usize);
+
+    let mut data = vec![0; width * height * channels * 4];
+    input.read_exact(&mut data)?;
+    let values: Vec<f64> = data
+        .chunks(4)
+        .map(|bytes| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            // a negative scale marks the data as little-endian
+            let v = if scale < 0. {
+                f32::from_le_bytes(bytes)
+            } else {
+                f32::from_be_bytes(bytes)
+            };
+            v as f64
+        })
+        .collect();
+    let mut colors = Vec::with_capacity(width * height);
+    // rows are stored from the bottom up
+    for row in values.chunks(width * channels).rev() {
+        colors.extend(row.chunks(channels).map(|c| match c {
+            &[r, g, b] => Color::new(r, g, b),
+            _ => Color::new(c[0], c[0], c[0]),
+        }));
+    }
+    Ok((width, height, colors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halfs() {
+        assert_eq!(to_half(0.), 0);
+        assert_eq!(to_half(-0.), 0x8000);
+        assert_eq!(to_half(1.), 0x3c00);
+        assert_eq!(to_half(-2.), 0xc000);
+        assert_eq!(to_half(0.1), 0x2e66);
+        assert_eq!(to_half(65504.), 0x7bff);
+        assert_eq!(to_half(1e6), 0x7c00);
+        assert_eq!(to_half(f32::INFINITY), 0x7c00);
+        assert!(to_half(f32::NAN) & 0x3ff != 0);
+        // smallest subnormal, and something too small to represent
+        assert_eq!(to_half(2f32.powi(-24)), 1);
+        assert_eq!(to_half(2f32.powi(-26)), 0);
+    }
+
+    #[test]
+    fn rgbe() {
+        assert_eq!(to_rgbe(Color::new(0., 0., 0.)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(Color::new(1., 0.5, 0.25)), [128, 64, 32, 129]);
+        assert_eq!(to_rgbe(Color::new(0.75, 0., -1.)), [192, 0, 0, 128]);
+        let [r, _, _, e] = to_rgbe(Color::new(1000., 0., 0.));
+        let decoded = (r as f64 + 0.5) * 2f64.powi(e as i32 - 136);
+        assert!((decoded - 1000.).abs() < 1000. / 128.);
+    }
+
+    #[test]
+    fn pfm_rows_flipped() {
+        let colors = [Color::new(1., 1., 1.), Color::new(2., 2., 2.)];
+        let mut out = vec![];
+        write_pfm(&mut out, 1, 2, &colors).unwrap();
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..header.len() + 4], &2f32.to_le_bytes());
+        assert_eq!(out.len(), header.len() + 2 * 3 * 4);
+    }
+
+    #[test]
+    fn exr_layout() {
+        let colors = vec![Color::new(1., 2., 3.); 6];
+        for &half in &[true, false] {
+            let mut out = vec![];
+            write_exr(&mut out, 3, 2, &colors, half).unwrap();
+            assert_eq!(&out[..4], &[0x76, 0x2f, 0x31, 0x01]);
+            let size = if half { 2 } else { 4 };
+            let block = 8 + 3 * 3 * size;
+            // the offset table sits right before the first block, and the
+            // last block ends the file
+            let last = out.len() - block;
+            let table = last - block - 8 * 2;
+            let mut offset = [0; 8];
+            offset.copy_from_slice(&out[table + 8..table + 16]);
+            assert_eq!(u64::from_le_bytes(offset) as usize, last);
+            assert_eq!(&out[last - block..last - block + 4], &0i32.to_le_bytes());
+            assert_eq!(&out[last..last + 4], &1i32.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let colors: Vec<Color> = (0..12)
+            .map(|i| Color::new(i as f64, 0.5, 1000. / (i + 1) as f64))
+            .collect();
+        let mut out = vec![];
+        write_pfm(&mut out, 4, 3, &colors).unwrap();
+        let (width, height, read) = read_pfm(&out[..]).unwrap();
+        assert_eq!((width, height), (4, 3));
+        for (a, b) in colors.iter().zip(&read) {
+            assert!((a.b - b.b).abs() < 1e-3 * a.b, "{:?} vs {:?}", a, b);
+        }
+
+        let mut out = vec![];
+        write_rgbe(&mut out, 4, 3, &colors).unwrap();
+        let (width, height, read) = read_rgbe(&out[..]).unwrap();
+        assert_eq!((width, height), (4, 3));
+        for (a, b) in colors.iter().zip(&read) {
+            assert!((a.b - b.b).abs() < a.b / 64., "{:?} vs {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn reads_run_length_encoding() {
+        let mut file = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        file.extend_from_slice(&[2, 2, 0, 8]);
+        // red as a run, green and blue given one by one, then the exponent
+        file.extend_from_slice(&[136, 128]);
+        for _ in 0..2 {
+            file.push(8);
+            file.extend_from_slice(&[0, 64, 0, 64, 0, 64, 0, 64]);
+        }
+        file.extend_from_slice(&[136, 129]);
+        let (width, height, colors) = read_rgbe(&file[..]).unwrap();
+        assert_eq!((width, height), (8, 1));
+        assert!((colors[0].r - 1.).abs() < 0.01);
+        assert!((colors[1].g - 0.5).abs() < 0.01);
+        assert_eq!(colors[2].b, colors[0].b);
+    }
+}
@@ -0,0 +1,459 @@
+//! Loading scenes from TOML scene-description files.
+//!
+//! A scene file has one `[camera]` table and any number of `[[objects]]` and
+//! `[[lights]]` entries. Vectors and colors are written as three-element
+//! arrays, and enum values (shapes and materials) are tables keyed by the
+//! variant name:
+//!
+//! ```toml
+//! [camera]
+//! pos = [0, 0, -4]
+//! facing = [0, 0, 1]
+//! up = [0, 1, 0]
+//! fov = 36 # degrees from the center to the edge of the image
+//!
+//! [[lights]]
+//! pos = [1.5, 1.5, -1.5]
+//! color = [1, 1, 1]
+//! # optional: fade faster than the inverse square law, by a factor of
+//! # 1 / (1 + attenuation * d²) over the distance d to what the light lands on
+//! attenuation = 0.1
+//!
+//! # area lights take a shape instead, and give off light from its front:
+//! # the outside of a sphere, or the side a quad's first edge turns
+//! # anticlockwise towards its second
+//! [[lights]]
+//! shape = { quad = { corner = [-0.5, 1.9, -0.5], edges = [[1, 0, 0], [0, 0, 1]] } }
+//! color = [4, 4, 4]
+//!
+//! # spot lights are point lights that shine in a cone, given in degrees from
+//! # the middle to the edge, and can fade out over the last few degrees
+//! [[lights]]
+//! pos = [0, 1.9, 0]
+//! facing = [0, -1, 0]
+//! angle = 30
+//! falloff = 5 # optional
+//! color = [2, 2, 2]
+//!
+//! # directional lights shine from far away, like the sun, along `direction`.
+//! # Their light comes in over the finite objects, the camera, and the part of
+//! # any infinite plane around it.
+//! [[lights]]
+//! direction = [0.3, -1, 0.2]
+//! color = [0.5, 0.5, 0.5]
+//!
+//! [[objects]]
+//! shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+//! material = { diffuse = [1, 0.5, 0.5] }
+//!
+//! # dielectrics are smooth glass or water, reflecting and refracting at an
+//! # index of refraction, and can tint what passes through them
+//! [[objects]]
+//! shape = { sphere = { center = [-1.5, -1.5, 0], radius = 0.5 } }
+//! material = { dielectric = { ior = 1.5, tint = [0.9, 1, 0.9] } }
+//!
+//! # conductors are rough metals, given by the complex index of refraction
+//! # eta + i k of each channel, here gold's. A roughness near 0 is close to a
+//! # mirror and 1 is very dull, with facets spread out as in "ggx" (the
+//! # default) or "beckmann".
+//! [[objects]]
+//! shape = { sphere = { center = [0, 1.5, 0], radius = 0.5 } }
+//! material = { conductor = { roughness = 0.2, eta = [0.14, 0.37, 1.44], k = [3.98, 2.39, 1.6], distribution = "beckmann" } }
+//!
+//! # rough dielectrics are frosted glass, and take a roughness like conductors
+//! [[objects]]
+//! shape = { sphere = { center = [0, -1.5, 0], radius = 0.5 } }
+//! material = { rough_dielectric = { roughness = 0.3, ior = 1.5 } }
+//!
+//! # emissive objects glow like area lights, so can't be infinite planes
+//! [[objects]]
+//! shape = { sphere = { center = [1.5, -1.5, 0], radius = 0.25 } }
+//! material = { emissive = { color = [1, 0.8, 0.6], strength = 4 } }
+//!
+//! [[objects]]
+//! shape = { triangle = { vertices = [[-1, 0, 2], [1, 0, 2], [0, 1, 2]] } }
+//! material = { diffuse = [1, 1, 1] }
+//!
+//! # indexed meshes can have one normal and one uv per position
+//! [[objects]]
+//! shape = { mesh = { positions = [[0, 0, 3], [1, 0, 3], [0, 1, 3]], triangles = [[0, 1, 2]] } }
+//! material = { diffuse = [1, 1, 1] }
+//!
+//! [[objects]]
+//! shape = { plane = { center = [0, -2, 0], normal = [0, 1, 0] } }
+//! # specular materials take a color and a Phong exponent, and combined
+//! # materials take a list of weighted sub-materials
+//! material = { combined = [
+//!     [0.2, { diffuse = [1, 1, 1] }],
+//!     [0.8, { specular = { color = [1, 1, 1], exponent = 5 } }],
+//! ] }
+//! ```
+//!
+//! Meshes can also be loaded from Wavefront OBJ files, with their materials
+//! taken from the MTL libraries they reference unless one is given here. The
+//! path is relative to the scene file, and the model can be scaled and then
+//! moved:
+//!
+//! ```toml
+//! [[models]]
+//! file = "models/cube.obj"
+//! scale = 0.5
+//! offset = [0, -1.5, 0]
+//! material = { diffuse = [1, 1, 1] } # optional
+//! ```
+//!
+//! An environment map can surround the scene, lighting it from every direction
+//! and showing wherever the camera sees past everything else. It is read from
+//! an equirectangular .hdr or .pfm file, relative to the scene file, whose top
+//! row is straight up. It can be turned around the vertical axis by some
+//! degrees, and made brighter or dimmer:
+//!
+//! ```toml
+//! [environment]
+//! file = "sky.hdr"
+//! rotation = 90 # optional
+//! intensity = 2 # optional
+//! ```
+//!
+//! Like a directional light's, its light comes in over the finite objects, the
+//! camera, and the part of any infinite plane around it.
+//!
+//! Unknown keys are rejected, and errors name the offending key along with the
+//! line of the table it was found in.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+use crate::camera::Camera;
+use crate::environment::Environment;
+use crate::material::Material;
+use crate::obj::load_obj;
+use crate::scene::{Light, Object, Scene, Shape};
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A problem on a given line of some other kind of file
+    Syntax(PathBuf, usize, String),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            LoadError::Parse(path, e) => write!(f, "invalid scene {}: {}", path.display(), e),
+            LoadError::Syntax(path, line, e) => write!(f, "{}:{}: {}", path.display(), line, e),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(_, e) => Some(e),
+            LoadError::Parse(_, e) => Some(e),
+            LoadError::Syntax(..) => None,
+        }
+    }
+}
+
+/// The top level of a scene file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: Camera,
+    #[serde(default)]
+    objects: Vec<Object>,
+    #[serde(default)]
+    lights: Vec<Light>,
+    #[serde(default)]
+    models: Vec<Model>,
+    environment: Option<EnvironmentDesc>,
+}
+
+/// An OBJ file to add to the scene, relative to the scene file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Model {
+    file: PathBuf,
+    /// Use this for every mesh instead of the materials from the file
+    material: Option<Material>,
+    scale: Option<f64>,
+    offset: Option<Vector3<f64>>,
+}
+
+/// An environment map surrounding the scene, relative to the scene file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    file: PathBuf,
+    /// Degrees to turn the map around the vertical axis
+    rotation: Option<f64>,
+    intensity: Option<f64>,
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, LoadError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_owned(), e))?;
+    parse_scene(&text, path)
+}
+
+/// Parse the text of a scene file. Models are looked up relative to `path`.
+pub fn parse_scene(text: &str, path: &Path) -> Result<Scene, LoadError> {
+    let file: SceneFile = toml::from_str(text).map_err(|e| LoadError::Parse(path.to_owned(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut objects = file.objects;
+    for model in file.models {
+        let scale = model.scale.unwrap_or(1.);
+        let offset = model.offset.unwrap_or_else(Vector3::zeros);
+        for mut object in load_obj(dir.join(&model.file))? {
+            if let Shape::Mesh(mesh) = &object.shape {
+                object.shape = Shape::Mesh(Arc::new(mesh.transformed(scale, offset)));
+            }
+            if let Some(material) = &model.material {
+                object.material = material.clone();
+            }
+            objects.push(object);
+        }
+    }
+    let mut lights = file.lights;
+    if let Some(environment) = file.environment {
+        let path = dir.join(&environment.file);
+        let rotation = environment.rotation.unwrap_or(0.).to_radians();
+        let map = Environment::load(&path, rotation).map_err(|e| LoadError::Io(path, e))?;
+        lights.push(Light::environment(map, environment.intensity.unwrap_or(1.)));
+    }
+    Ok(Scene::new(file.camera, objects, lights))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::microfacet::Distribution;
+
+    #[test]
+    fn default_scene() {
+        let scene = load_scene("scenes/default.toml").unwrap();
+        assert_eq!(scene.lights.len(), 2);
+        assert_eq!(scene.objects().len(), 5);
+        assert!(matches!(scene.objects()[4].shape, Shape::Plane { .. }));
+        match &scene.objects()[0].material {
+            Material::Combined(mats) => assert_eq!(mats.len(), 2),
+            m => panic!("expected a combined material, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn area_lights() {
+        let text = "
+[camera]
+pos = [0, 0, -4]
+facing = [0, 0, 1]
+up = [0, 1, 0]
+fov = 36
+
+[[lights]]
+shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+color = [1, 1, 1]
+";
+        let scene = parse_scene(text, Path::new("test.toml")).unwrap();
+        // the light can be seen, so it is one of the objects too
+        assert_eq!(scene.objects().len(), 1);
+        assert!((scene.lights[0].point_pdf(Vector3::zeros()) - 1. / (4. * PI)).abs() < 1e-9);
+
+        let text = text.replace("color", "pos = [0, 0, 0]\ncolor");
+        let err = parse_scene(&text, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("either a pos, a shape or a direction"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn spot_directional_and_emissive() {
+        let text = "
+[camera]
+pos = [0, 0, -4]
+facing = [0, 0, 1]
+up = [0, 1, 0]
+fov = 36
+
+[[lights]]
+pos = [0, 2, 0]
+facing = [0, -1, 0]
+angle = 30
+falloff = 5
+color = [1, 1, 1]
+attenuation = 0.1
+
+[[lights]]
+direction = [0, -1, 0]
+color = [1, 1, 1]
+
+[[objects]]
+shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+material = { emissive = { color = [1, 1, 1], strength = 2 } }
+";
+        let scene = parse_scene(text, Path::new("test.toml")).unwrap();
+        // the glowing sphere is a light as well as an object
+        assert_eq!(scene.lights.len(), 3);
+        assert_eq!(scene.objects().len(), 1);
+        assert!(scene.lights[1].is_distant());
+        assert!((scene.lights[2].color.luminance() - 2.).abs() < 1e-9);
+        assert_eq!(scene.lights[0].attenuation, 0.1);
+        assert_eq!(scene.lights[1].attenuation, 0.);
+
+        let attenuated_sun = text.replace(
+            "direction = [0, -1, 0]\n",
+            "direction = [0, -1, 0]\nattenuation = 1\n",
+        );
+        let err = parse_scene(&attenuated_sun, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("can't have an attenuation"), "{}", err);
+
+        let no_angle = text
+            .replace("falloff = 5\n", "")
+            .replace("angle = 30\n", "");
+        let err = parse_scene(&no_angle, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a facing and an angle"), "{}", err);
+
+        for angle in ["0", "-10", "180"] {
+            let bad_angle = text.replace("angle = 30\n", &format!("angle = {}\n", angle));
+            let err = parse_scene(&bad_angle, Path::new("test.toml"))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("more than 0 and less than 180"), "{}", err);
+        }
+        let bad_falloff = text.replace("falloff = 5\n", "falloff = -5\n");
+        let err = parse_scene(&bad_falloff, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("falloff can't be negative"), "{}", err);
+
+        let plane = text.replace(
+            "sphere = { center = [0, 0, 0], radius = 1 }",
+            "plane = { center = [0, 0, 0], normal = [0, 1, 0] }",
+        );
+        let err = parse_scene(&plane, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("infinite plane"), "{}", err);
+    }
+
+    #[test]
+    fn dielectrics() {
+        let text = "
+[camera]
+pos = [0, 0, -4]
+facing = [0, 0, 1]
+up = [0, 1, 0]
+fov = 36
+
+[[objects]]
+shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+material = { dielectric = { ior = 1.5 } }
+";
+        let scene = parse_scene(text, Path::new("test.toml")).unwrap();
+        match scene.objects()[0].material {
+            Material::Dielectric { ior, tint } => {
+                assert_eq!(ior, 1.5);
+                assert_eq!(tint, Color::new(1., 1., 1.));
+            }
+            ref m => panic!("expected a dielectric, got {:?}", m),
+        }
+
+        let combined = text.replace(
+            "{ dielectric = { ior = 1.5 } }",
+            "{ combined = [[1, { dielectric = { ior = 1.5 } }]] }",
+        );
+        let err = parse_scene(&combined, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("can't be part of a combined"), "{}", err);
+    }
+
+    #[test]
+    fn microfacet_materials() {
+        let text = "
+[camera]
+pos = [0, 0, -4]
+facing = [0, 0, 1]
+up = [0, 1, 0]
+fov = 36
+
+[[objects]]
+shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+material = { conductor = { roughness = 0.2, eta = [0.2, 0.9, 1.1], k = [3.9, 2.4, 2.1] } }
+
+[[objects]]
+shape = { sphere = { center = [0, 2, 0], radius = 1 } }
+material = { rough_dielectric = { roughness = 0.3, ior = 1.5, distribution = \"beckmann\" } }
+";
+        let scene = parse_scene(text, Path::new("test.toml")).unwrap();
+        match scene.objects()[0].material {
+            Material::Conductor {
+                distribution,
+                roughness,
+                ..
+            } => {
+                assert_eq!(distribution, Distribution::Ggx);
+                assert_eq!(roughness, 0.2);
+            }
+            ref m => panic!("expected a conductor, got {:?}", m),
+        }
+        match scene.objects()[1].material {
+            Material::RoughDielectric {
+                distribution, tint, ..
+            } => {
+                assert_eq!(distribution, Distribution::Beckmann);
+                assert_eq!(tint, Color::new(1., 1., 1.));
+            }
+            ref m => panic!("expected a rough dielectric, got {:?}", m),
+        }
+        assert!(scene.objects()[1].material.transmits());
+
+        let smooth = text.replace("roughness = 0.2", "roughness = 0");
+        let err = parse_scene(&smooth, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("roughness has to be more than 0"), "{}", err);
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let text = "
+[camera]
+pos = [0, 0, -4]
+facing = [0, 0, 1]
+up = [0, 1, 0]
+fov = 36
+
+[[objects]]
+shape = { sphere = { center = [0, 0, 0], radius = 1 } }
+material = { shiny = [1, 1, 1] }
+";
+        let err = parse_scene(text, Path::new("test.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("shiny"), "{}", err);
+        assert!(err.contains("objects.material"), "{}", err);
+        assert!(err.contains("line 8"), "{}", err);
+    }
+}
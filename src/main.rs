@@ -1,186 +1,320 @@
+mod bdpt;
+mod bootstrap;
+mod bsdf;
+mod bvh;
 mod camera;
 mod color;
+mod environment;
+mod erpt;
+mod export;
+mod hdr;
+mod loader;
 mod material;
+mod mesh;
+mod microfacet;
 mod mlt;
+mod mmlt;
+mod obj;
+mod pathtracer;
+mod ppm;
+mod progress;
+mod pssmlt;
 mod scene;
+mod settings;
+mod vcm;
 mod vector;
 
-use std::f64::consts::PI;
 use std::process::exit;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::sleep;
-use std::time::Duration;
-use std::usize;
+use std::time::{Duration, Instant};
 
-use lazy_static::lazy_static;
-use minifb::{Window, WindowOptions};
-use nalgebra::Vector3;
-use rayon::ThreadPoolBuilder;
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use structopt::StructOpt;
 
-use crate::camera::{Camera, ImageBuffer};
+use crate::bsdf::Bsdf;
+use crate::camera::ImageBuffer;
 use crate::color::Color;
-use crate::material::Material;
-use crate::mlt::{draw, Path};
-use crate::scene::{Light, Object, Scene, Shape};
-
-const WIDTH: usize = 640;
-const HEIGHT: usize = 640;
-const N_THREADS: usize = 8;
-
-const SAMPLES_PER_PIXEL: usize = 20;
+use crate::loader::load_scene;
+use crate::mlt::Path;
+use crate::progress::Progress;
+use crate::scene::{Emitter, Scene};
+use crate::settings::{Integrator, Options, RenderSettings};
+use crate::vector::Ray;
 
 // do not consider intersections closer than this. (mostly prevents shadow acne)
 const MIN_DIST: f64 = 0.001;
 
-// chance of adding another step to the traced path
-const CONTINUE_CHANCE: f64 = 0.5;
-
-// factor for light attenuation over distance
-const DISTANCE_FACTOR: f64 = 0.1;
-
-lazy_static! {
-    static ref IMAGE: ImageBuffer = ImageBuffer {
-        buffer: Mutex::new(vec![Color::new(0., 0., 0.); WIDTH * HEIGHT]),
-        width: WIDTH,
-        height: HEIGHT,
-    };
-    static ref SCENE: Scene = Scene {
-        camera: Camera::new(
-            Vector3::new(0., 0., -4.),
-            Vector3::new(0., 0., 1.),
-            Vector3::new(0., 1., 0.),
-            PI / 5.,
-        ),
-        lights: vec![
-            // Light {
-            //     pos: Vector3::new(-1.5, 1.5, -1.5),
-            //     color: Color::new(1., 0., 0.),
-            // },
-            Light {
-                pos: Vector3::new(0., 1.5, -1.5),
-                color: Color::new(0., 0., 1.),
-            },
-            Light {
-                pos: Vector3::new(1.5, 1.5, -1.5),
-                color: Color::new(1., 1., 1.),
-            },
-        ],
-        objects: vec![
-            Object {
-                shape: Shape::Sphere {
-                    center: Vector3::new(0., 0., 0.),
-                    radius: 1.,
-                },
-                material: Material::Combined(vec![
-                    (0.2, Material::Diffuse(Color::new(1., 0.5, 0.5))),
-                    (0.8, Material::Specular(Color::new(1., 0.5, 0.5), 100.)),
-                    ]),
-            },
-            Object {
-                shape: Shape::Sphere {
-                    center: Vector3::new(-1., -1., -1.),
-                    radius: 0.5,
-                },
-                material:Material::Diffuse(Color::new(0.5, 1., 0.5)),
-            },
-            Object {
-                shape: Shape::Sphere {
-                    center: Vector3::new(1., 1., -1.),
-                    radius: 0.2,
-                },
-                material: Material::Specular(Color::new(1., 1., 1.), 10.),
-            },
-            Object {
-                shape: Shape::Sphere {
-                    center: Vector3::new(0., 0., 0.),
-                    radius: 4.,
-                },
-                material: Material::Diffuse(Color::new(0.5, 0.5, 0.5)),
-            },
-            Object {
-                shape: Shape::Plane {
-                    center: Vector3::new(0., -2., 0.),
-                    normal: Vector3::new(0., 1., 0.),
-                },
-                material: Material::Combined(vec![
-                    (0.2, Material::Diffuse(Color::new(1., 1., 1.))),
-                    (0.8, Material::Specular(Color::new(1., 1., 1.), 5.)),
-                    ]),
-            },
-        ],
-    };
-}
+/// How many times to follow a ray from the camera through specular surfaces
+/// before giving up on it
+const MAX_SPECULAR_BOUNCES: usize = 16;
 
 fn main() {
-    let mut window = Window::new(
-        "Test - ESC to exit",
-        WIDTH,
-        HEIGHT,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
+    let options = Options::from_args();
+    let settings = options.render;
+    if let Some(Err(e)) = options.output.as_deref().map(export::check_format) {
+        eprintln!("{}", e);
+        exit(1)
+    }
+    let scene = load_scene(&options.scene).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
     });
-
-    // // Limit to max ~60 fps update rate
-    window.limit_update_rate(Some(Duration::from_micros(16600)));
+    // the render jobs outlive this stack frame as far as rayon knows
+    let scene: &'static Scene = Box::leak(Box::new(scene));
+    let image: &'static ImageBuffer =
+        Box::leak(Box::new(ImageBuffer::new(settings.width, settings.height)));
 
     let pool = ThreadPoolBuilder::new()
-        .num_threads(N_THREADS)
+        .num_threads(settings.threads)
         .build()
         .unwrap();
-    // draw lights
-    for light in &SCENE.lights {
-        SCENE.camera.record_sample(
-            &Path {
-                camera: &SCENE.camera,
-                light,
-                normals: vec![],
-                objects: vec![],
-                points: vec![light.pos, SCENE.camera.pos],
-            },
-            &SCENE,
-            &IMAGE,
-            1.,
+    draw_lights(&pool, scene, image, &settings);
+
+    let time_limit = options.time_limit.map(Duration::from_secs_f64);
+    let progress = spawn_jobs(&pool, scene, image, settings, options.sample_limit);
+
+    if options.headless {
+        println!("rendering...");
+        if !progress.wait(time_limit) {
+            println!("time limit reached, stopping...");
+            progress.cancel();
+            progress.wait(None);
+        }
+        println!("recorded {} samples", progress.samples());
+    } else {
+        let mut window = Window::new(
+            "Test - ESC to exit, S to save a snapshot",
+            settings.width,
+            settings.height,
+            WindowOptions::default(),
         )
-    }
+        .unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
 
-    println!("spawning threads...");
-    for i in 0..WIDTH {
-        let x = 2. * (i as i32 - WIDTH as i32 / 2) as f64 / WIDTH as f64;
-        for j in 0..HEIGHT {
-            let y = 2. * (j as i32 - HEIGHT as i32 / 2) as f64 / WIDTH as f64;
-            // do this to account for multiple lights
-            for light in &SCENE.lights {
-                pool.spawn(move || {
-                    draw(
-                        SAMPLES_PER_PIXEL / SCENE.lights.len(),
-                        x,
-                        y,
-                        light,
-                        &SCENE,
-                        &IMAGE,
-                    )
-                });
+        // // Limit to max ~60 fps update rate
+        window.limit_update_rate(Some(Duration::from_micros(16600)));
+
+        println!("rendering...");
+        let start = Instant::now();
+        let mut buffer = vec![0u32; settings.width * settings.height];
+        let mut snapshots = 0;
+        while window.is_open() {
+            if let Ok(colors) = image.buffer.try_lock() {
+                export::to_preview(&colors, options.save.exposure, &mut buffer);
             }
+            if window.is_key_pressed(Key::S, KeyRepeat::No) {
+                snapshots += 1;
+                let path = export::snapshot_path(options.output.as_deref(), snapshots);
+                match export::save(image, &path, &options.save) {
+                    Ok(()) => println!("saved {}", path.display()),
+                    Err(e) => eprintln!("could not save {}: {}", path.display(), e),
+                }
+            }
+            if matches!(time_limit, Some(limit) if start.elapsed() > limit) {
+                progress.cancel();
+            }
+            window.set_title(&format!(
+                "Test - ESC to exit, S to save a snapshot ({:.0}%)",
+                100. * progress.fraction()
+            ));
+
+            // Unwrap here as we want this code to exit if it fails. Real applications may want to handle this in a different way
+            window
+                .update_with_buffer(&buffer, image.width, image.height)
+                .unwrap();
+            // Sleep for a frame to let the renderer do it's work (this makes sure we aren't holding the buffer mutex open)
+            sleep(Duration::from_micros(16600));
         }
+        // let the jobs that are already running finish before saving
+        progress.cancel();
+        progress.wait(None);
     }
-    println!("rendering...");
-    let mut buffer = vec![0u32; WIDTH * HEIGHT];
-    while window.is_open() {
-        if let Ok(colors) = IMAGE.buffer.try_lock() {
-            for (i, c) in colors.iter().enumerate() {
-                buffer[i] = (*c).into();
-            }
+
+    if let Some(path) = &options.output {
+        if let Err(e) = export::save(image, path, &options.save) {
+            eprintln!("could not save {}: {}", path.display(), e);
+            exit(1)
         }
+    }
+    exit(0)
+}
+
+/// Draw the lights where the camera sees them directly, which the integrators
+/// leave out. A point or spot light lands on a single pixel, while an area light is
+/// traced to the camera from as many points on it as there are samples in the
+/// whole image. An environment map, and any light seen by way of specular
+/// surfaces, is drawn by tracing rays out from the camera instead.
+fn draw_lights(pool: &ThreadPool, scene: &Scene, image: &ImageBuffer, settings: &RenderSettings) {
+    let pixels = settings.width * settings.height;
+    for light in &scene.lights {
+        let samples = match light.emitter {
+            Emitter::Point(_) | Emitter::Spot { .. } => 1,
+            Emitter::Area(_) => pixels * settings.samples_per_pixel,
+            // only a camera pointed exactly down its beam could see one
+            Emitter::Directional { .. } | Emitter::Environment { .. } => 0,
+        };
+        pool.install(|| {
+            (0..samples)
+                .into_par_iter()
+                .for_each_init(rand::thread_rng, |rng, _| {
+                    let (point, normal) = light.sample_point(rng);
+                    // each point lands on a single pixel, so spread over its area
+                    let weight = pixels as f64
+                        / (settings.film_area() * samples as f64 * light.point_pdf(normal));
+                    let path = Path::connect((point, normal, light), &[], &[], &scene.camera);
+                    scene
+                        .camera
+                        .record_sample(&path, scene, image, settings, weight);
+                })
+        });
+    }
+    let environment = scene
+        .lights
+        .iter()
+        .any(|light| matches!(light.emitter, Emitter::Environment { .. }));
+    if environment
+        || scene
+            .objects()
+            .iter()
+            .any(|o| o.material.specular().is_some())
+    {
+        draw_specular(pool, scene, image, settings);
+    }
+}
 
-        // Unwrap here as we want this code to exit if it fails. Real applications may want to handle this in a different way
-        window
-            .update_with_buffer(&buffer, IMAGE.width, IMAGE.height)
-            .unwrap();
-        // Sleep for a frame to let the renderer do it's work (this makes sure we aren't holding the buffer mutex open)
-        sleep(Duration::from_micros(16600));
+/// Add to every pixel the light the camera sees through any number of
+/// specular surfaces: the environment map wherever rays leave the scene, and
+/// lights they run into after at least one specular bounce
+fn draw_specular(pool: &ThreadPool, scene: &Scene, image: &ImageBuffer, settings: &RenderSettings) {
+    let samples = settings.samples_per_pixel.max(1);
+    let colors: Vec<Color> = pool.install(|| {
+        (0..settings.width * settings.height)
+            .into_par_iter()
+            .map_init(rand::thread_rng, |rng, pixel| {
+                let mut total = Color::new(0., 0., 0.);
+                for _ in 0..samples {
+                    let dir = scene.camera.sample_pixel(pixel, settings, rng);
+                    let mut ray = Ray::new(scene.camera.pos, dir);
+                    let mut throughput = Color::new(1., 1., 1.);
+                    for bounce in 0..MAX_SPECULAR_BOUNCES {
+                        let hit = scene.cast(ray);
+                        if bounce > 0 || hit.is_none() {
+                            total += throughput * scene.emitted(ray, hit);
+                        }
+                        let (t, normal, object, _) = match hit {
+                            Some(hit) if hit.2.material.specular().is_some() => hit,
+                            _ => break,
+                        };
+                        let shading = object.material.at(normal);
+                        let (pdf, dir) = shading.sample(-ray.dir, rng);
+                        let cos = dir.dot(&normal).abs();
+                        throughput *= shading.eval(dir, -ray.dir) * (cos / pdf);
+                        ray = Ray::new(ray.of(t), dir);
+                    }
+                }
+                total / samples as f64
+            })
+            .collect()
+    });
+    let mut buffer = image.buffer.lock().unwrap();
+    for (pixel, color) in buffer.iter_mut().zip(colors) {
+        *pixel += color;
     }
+}
 
-    exit(0)
+/// Bootstrap the chosen integrator, then queue one chain per pixel on the
+/// pool (or for the path tracers and energy redistribution, one job per
+/// pixel, and for photon mapping and vertex merging, one job per pass),
+/// returning a handle to wait on them with.
+fn spawn_jobs(
+    pool: &ThreadPool,
+    scene: &'static Scene,
+    image: &'static ImageBuffer,
+    settings: RenderSettings,
+    sample_limit: Option<usize>,
+) -> &'static Progress {
+    println!("bootstrapping...");
+    let rng = &mut rand::thread_rng();
+    let chain: Box<dyn Fn(usize) + Send + Sync> = match settings.integrator {
+        Integrator::Mlt => {
+            let seeds = mlt::bootstrap(scene, &settings, rng);
+            Box::new(move |n| {
+                mlt::draw(n, &seeds, scene, image, &settings, &mut rand::thread_rng())
+            })
+        }
+        Integrator::Pssmlt => {
+            let seeds = pssmlt::bootstrap(scene, &settings, rng);
+            Box::new(move |n| {
+                pssmlt::draw(n, &seeds, scene, image, &settings, &mut rand::thread_rng())
+            })
+        }
+        Integrator::Mmlt => {
+            let seeds = mmlt::bootstrap(scene, &settings, rng);
+            Box::new(move |n| {
+                mmlt::draw(n, &seeds, scene, image, &settings, &mut rand::thread_rng())
+            })
+        }
+        Integrator::Erpt => {
+            let b = mlt::estimate_b(scene, &settings, rng);
+            let next = AtomicUsize::new(0);
+            Box::new(move |n| {
+                let pixel =
+                    next.fetch_add(1, Ordering::Relaxed) % (settings.width * settings.height);
+                erpt::draw(
+                    pixel,
+                    n,
+                    b,
+                    scene,
+                    image,
+                    &settings,
+                    &mut rand::thread_rng(),
+                )
+            })
+        }
+        Integrator::PathTracer | Integrator::Bdpt => {
+            let draw = match settings.integrator {
+                Integrator::Bdpt => bdpt::draw,
+                _ => pathtracer::draw,
+            };
+            let next = AtomicUsize::new(0);
+            Box::new(move |n| {
+                let pixel =
+                    next.fetch_add(1, Ordering::Relaxed) % (settings.width * settings.height);
+                draw(pixel, n, scene, image, &settings, &mut rand::thread_rng())
+            })
+        }
+        Integrator::Ppm | Integrator::Vcm => {
+            let draw = match settings.integrator {
+                Integrator::Vcm => vcm::draw,
+                _ => ppm::draw,
+            };
+            let next = AtomicUsize::new(0);
+            Box::new(move |_| {
+                let pass = next.fetch_add(1, Ordering::Relaxed);
+                draw(pass, scene, image, &settings, &mut rand::thread_rng())
+            })
+        }
+    };
+    let chain: &'static (dyn Fn(usize) + Sync) = Box::leak(chain);
+    let pixels = settings.width * settings.height;
+    let (chains, n) = match settings.integrator {
+        // every pass takes one sample per pixel
+        Integrator::Ppm | Integrator::Vcm => (settings.samples_per_pixel, pixels),
+        _ => (pixels, settings.samples_per_pixel),
+    };
+    let progress: &'static Progress = Box::leak(Box::new(Progress::new(chains, sample_limit)));
+    println!("spawning threads...");
+    for _ in 0..chains {
+        pool.spawn(move || {
+            progress.run(|| {
+                chain(n);
+                n
+            })
+        });
+    }
+    progress
 }
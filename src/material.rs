@@ -1,64 +1,424 @@
 use std::f64::consts::{PI, TAU};
-use std::fmt::Debug;
-use std::ops::Add;
 
-use nalgebra::{Rotation3, Vector3};
+use nalgebra::Vector3;
 use rand::Rng;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
 
+use crate::bsdf::{along, fresnel, fresnel_conductor, mirror, Bsdf};
 use crate::color::Color;
+use crate::microfacet::{Distribution, Microfacet};
+use crate::vector::{around, reflect, refract, spherical};
 
-#[derive(Debug, Clone)]
+/// Specular lobes at least this sharp count as glossy
+const GLOSSY_EXPONENT: f64 = 10.;
+/// Rough metals at least this smooth count as glossy, which is about as
+/// sharp as a Phong lobe with `GLOSSY_EXPONENT`
+const GLOSSY_ROUGHNESS: f64 = 0.4;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Material {
     Diffuse(Color),
+    #[serde(deserialize_with = "phong")]
     Specular(Color, f64),
+    #[serde(deserialize_with = "combined")]
     Combined(Vec<(f64, Material)>),
+    /// Glows with radiance `color` times `strength` from its front, like an
+    /// area light, and reflects nothing
+    #[serde(deserialize_with = "emission")]
+    Emissive(Color, f64),
+    /// Smooth glass or water: reflects some light in the mirror direction and
+    /// refracts the rest, tinted by `tint`, in proportion to the Fresnel
+    /// equations. The inside of the surface is the denser side, with index
+    /// of refraction `ior`.
+    Dielectric {
+        ior: f64,
+        #[serde(default = "white")]
+        tint: Color,
+    },
+    /// A metal whose surface is made of tiny mirror facets, each reflecting
+    /// as the Fresnel equations say for the complex index of refraction
+    /// `eta` + i `k` in each channel
+    Conductor {
+        #[serde(default)]
+        distribution: Distribution,
+        #[serde(deserialize_with = "roughness")]
+        roughness: f64,
+        eta: Color,
+        k: Color,
+    },
+    /// Frosted glass: a dielectric whose surface is made of tiny facets,
+    /// each reflecting and refracting like a smooth one
+    #[serde(rename = "rough_dielectric")]
+    RoughDielectric {
+        #[serde(default)]
+        distribution: Distribution,
+        #[serde(deserialize_with = "roughness")]
+        roughness: f64,
+        ior: f64,
+        #[serde(default = "white")]
+        tint: Color,
+    },
 }
 
-impl Material {
-    /// Bidirectional scattering distribution function -- the probability that a
-    /// light ray incoming at phi_in will be reflected in the direction angled
-    /// by phi_out and theta (theta is measured from the incoming source)
-    /// Measured in three color channels.
-    /// phi is the angle from the normal axis.
-    pub fn bsdf(&self, phi_in: f64, theta: f64, phi_out: f64) -> Color {
+impl Bsdf for Material {
+    fn eval(&self, wi: Vector3<f64>, wo: Vector3<f64>) -> Color {
+        if let &Self::Dielectric { ior, tint } = self {
+            // the values of its delta functions, the mirror and refracted
+            // directions being all it gives light to. They're taken per unit
+            // of projected solid angle times the index squared, which
+            // refraction keeps the same on either side, so they come out the
+            // same whichever way the direction was sampled. Radiance ends up
+            // squeezed into a narrower cone going into a denser medium.
+            let (reflected, refracted) = dielectric_lobes(ior, wo);
+            let index = if wo[2] > 0. { 1. } else { ior };
+            return if along(wi, mirror(wo)) {
+                Color::new(1., 1., 1.) * (reflected * index * index)
+            } else if refract(wo, Vector3::z(), ior).is_some_and(|t| along(wi, t)) {
+                tint * (refracted * index * index)
+            } else {
+                Color::new(0., 0., 0.)
+            };
+        }
+        if let &Self::RoughDielectric {
+            distribution,
+            roughness,
+            ior,
+            tint,
+        } = self
+        {
+            let facets = Microfacet::new(distribution, roughness);
+            return rough_dielectric(facets, ior, tint, wi, wo);
+        }
+        if wi[2] <= 0. || wo[2] <= 0. {
+            return Color::new(0., 0., 0.);
+        }
         match self {
-            // we don't need to weight by the sine because points are generated
-            // uniformly on the plane, just converted to angles for convenience
-            &Self::Diffuse(color) => color, //* phi_out.sin(),
+            // both are normalized so that they never reflect more light than
+            // comes in
+            &Self::Diffuse(color) => color / PI,
             &Self::Specular(color, alpha) => {
-                // dot product of outgoing vector with reflection, raised to exponent
-                let dot =
-                    -theta.cos() * phi_in.sin() * phi_out.sin() + phi_in.cos() * phi_out.cos();
-                color * dot.abs().powf(alpha)
+                // how close the outgoing direction is to the mirror reflection
+                color * ((alpha + 2.) / TAU * mirror(wi).dot(&wo).max(0.).powf(alpha))
+            }
+            &Self::Conductor {
+                distribution,
+                roughness,
+                eta,
+                k,
+            } => {
+                let facets = Microfacet::new(distribution, roughness);
+                let h = (wi + wo).normalize();
+                let g = facets.d(h) * facets.g(wi, wo) / (4. * wi[2] * wo[2]);
+                fresnel_conductor(wo.dot(&h), eta, k) * g
+            }
+            Self::Combined(mats) => mats.iter().map(|(w, m)| m.eval(wi, wo) * *w).sum(),
+            Self::Emissive(..) | Self::Dielectric { .. } | Self::RoughDielectric { .. } => {
+                Color::new(0., 0., 0.)
             }
-            Self::Combined(mats) => mats
-                .iter()
-                .map(|(w, m)| m.bsdf(phi_in, theta, phi_out) * *w)
-                .sum(),
         }
     }
-    fn color(&self) -> Color {
+    /// Dielectrics pick between reflecting and refracting by how much light
+    /// goes each way, rough surfaces pick from the facets they can see,
+    /// Phong lobes pick around their mirror direction, and combined
+    /// materials pick one of their parts by its weight. Everything else
+    /// picks uniformly from the hemisphere above the surface.
+    fn sample<R: Rng + ?Sized>(&self, wo: Vector3<f64>, rng: &mut R) -> (f64, Vector3<f64>) {
+        let wi = match self {
+            &Self::Dielectric { ior, .. } => {
+                let (reflected, _) = dielectric_lobes(ior, wo);
+                match refract(wo, Vector3::z(), ior) {
+                    Some(t) if rng.gen::<f64>() >= reflected => t,
+                    _ => mirror(wo),
+                }
+            }
+            &Self::Specular(_, alpha) => sample_lobe(mirror(wo), alpha, rng),
+            &Self::Conductor {
+                distribution,
+                roughness,
+                ..
+            } => {
+                let (up, side) = above(wo);
+                let m = Microfacet::new(distribution, roughness).sample(up, rng);
+                reflect(up, m) * side
+            }
+            &Self::RoughDielectric {
+                distribution,
+                roughness,
+                ior,
+                ..
+            } => {
+                let (up, side) = above(wo);
+                let eta = if side > 0. { ior } else { 1. / ior };
+                let m = Microfacet::new(distribution, roughness).sample(up, rng);
+                let reflected = fresnel(up.dot(&m), eta);
+                let wi = match refract(up, m, eta) {
+                    Some(t) if rng.gen::<f64>() >= reflected => t,
+                    _ => reflect(up, m),
+                };
+                wi * side
+            }
+            Self::Combined(mats) if !mats.is_empty() => {
+                let total: f64 = mats.iter().map(|(w, _)| w).sum();
+                let mut pick = rng.gen::<f64>() * total;
+                let (_, part) = mats
+                    .iter()
+                    .find(|(w, _)| {
+                        pick -= w;
+                        pick < 0.
+                    })
+                    .unwrap_or(&mats[mats.len() - 1]);
+                part.sample(wo, rng).1
+            }
+            // Archimedes' hat-box theorem lets us generate a z-value and convert it to an angle
+            _ => spherical(rng.gen_range(0. ..1.), rng.gen_range(0. ..TAU)),
+        };
+        (self.pdf(wi, wo), wi)
+    }
+    /// For a dielectric, the probability of picking the mirror or refracted
+    /// direction instead, in the same units as the delta functions `eval`
+    /// gives
+    fn pdf(&self, wi: Vector3<f64>, wo: Vector3<f64>) -> f64 {
         match self {
-            &Self::Diffuse(color) => color,
-            &Self::Specular(color, _) => color,
-            Self::Combined(mats) => mats.iter().map(|(w, m)| m.color() * *w).sum(),
+            &Self::Dielectric { ior, .. } => {
+                let (reflected, refracted) = dielectric_lobes(ior, wo);
+                let index = if wi[2] > 0. { 1. } else { ior };
+                let lobe = if along(wi, mirror(wo)) {
+                    reflected
+                } else if refract(wo, Vector3::z(), ior).is_some_and(|t| along(wi, t)) {
+                    refracted
+                } else {
+                    0.
+                };
+                lobe * index * index * wi[2].abs()
+            }
+            &Self::Specular(_, alpha) => lobe_pdf(mirror(wo), alpha, wi),
+            &Self::Conductor {
+                distribution,
+                roughness,
+                ..
+            } => {
+                let (up, side) = above(wo);
+                let facets = Microfacet::new(distribution, roughness);
+                reflection_pdf(facets, up, wi * side, |_| 1.)
+            }
+            &Self::RoughDielectric {
+                distribution,
+                roughness,
+                ior,
+                ..
+            } => {
+                let facets = Microfacet::new(distribution, roughness);
+                rough_dielectric_pdf(facets, ior, wo, wi)
+            }
+            Self::Combined(mats) if !mats.is_empty() => {
+                let total: f64 = mats.iter().map(|(w, _)| w).sum();
+                mats.iter().map(|(w, m)| w * m.pdf(wi, wo)).sum::<f64>() / total
+            }
+            _ => {
+                if wi[2] > 0. {
+                    1. / TAU
+                } else {
+                    0.
+                }
+            }
         }
     }
-    /// By default generate a random point on the hemisphere
-    pub fn propose<R: Rng + ?Sized>(
-        &self,
-        normal: Vector3<f64>,
-        rng: &mut R,
-    ) -> (f64, Vector3<f64>) {
-        let theta = rng.gen_range(0. ..TAU);
-        // Archimedes' hat-box theorem lets us generate a z-value and convert it to an angle
-        let z: f64 = rng.gen_range(0. ..1.);
-        let r = (1. - z * z).sqrt();
-
-        (
-            self.color().luminance() / (2. * PI),
-            Rotation3::rotation_between(&Vector3::z(), &normal).unwrap_or(Rotation3::identity())
-                * Vector3::new(theta.cos() * r, theta.sin() * r, z),
+}
+
+/// How much of the light arriving at a dielectric along `w` is reflected and
+/// how much refracted. The Fresnel equations give the same split for the
+/// refracted direction, so it doesn't matter which way the light goes.
+fn dielectric_lobes(ior: f64, w: Vector3<f64>) -> (f64, f64) {
+    let eta = if w[2] > 0. { ior } else { 1. / ior };
+    let reflected = fresnel(w[2].abs(), eta);
+    (reflected, 1. - reflected)
+}
+
+/// `w` turned over if need be to be above the surface, and the sign to turn
+/// directions over by
+fn above(w: Vector3<f64>) -> (Vector3<f64>, f64) {
+    if w[2] >= 0. {
+        (w, 1.)
+    } else {
+        (-w, -1.)
+    }
+}
+
+/// Density of picking `wi` by reflecting `wo` (above the surface) off a
+/// facet it can see, given the chance `kept` of reflecting off a facet at
+/// that cosine
+fn reflection_pdf(
+    facets: Microfacet,
+    wo: Vector3<f64>,
+    wi: Vector3<f64>,
+    kept: impl Fn(f64) -> f64,
+) -> f64 {
+    let h = (wi + wo).normalize();
+    let cos = wo.dot(&h);
+    if cos > 0. {
+        facets.pdf(wo, h) * kept(cos) / (4. * cos)
+    } else {
+        0.
+    }
+}
+
+/// The rough dielectric's BSDF (Walter et al. 2007), in terms of radiance
+/// like the smooth one
+fn rough_dielectric(
+    facets: Microfacet,
+    ior: f64,
+    tint: Color,
+    wi: Vector3<f64>,
+    wo: Vector3<f64>,
+) -> Color {
+    // work from the side `wo` is on, where the index is 1 and it's `eta`
+    // past the surface
+    let (wo, side) = above(wo);
+    let wi = wi * side;
+    let eta = if side > 0. { ior } else { 1. / ior };
+    if wi[2] > 0. {
+        let h = (wi + wo).normalize();
+        let reflected = fresnel(wo.dot(&h), eta);
+        let value = reflected * facets.d(h) * facets.g(wi, wo) / (4. * wi[2] * wo[2]);
+        return Color::new(1., 1., 1.) * value;
+    }
+    // the facet that refracts one into the other
+    let h = (wo + wi * eta).normalize();
+    let h = h * h[2].signum();
+    let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+    if cos_o <= 0. || cos_i >= 0. {
+        return Color::new(0., 0., 0.);
+    }
+    let refracted = 1. - fresnel(cos_o, eta);
+    let spread = cos_o * -cos_i / (wo[2] * -wi[2] * (cos_o + eta * cos_i).powi(2));
+    tint * (refracted * facets.d(h) * facets.g(wi, wo) * spread)
+}
+
+/// Density of a rough dielectric picking `wi` for `wo`, whichever way it
+/// went: the same direction can come from reflecting off one facet or
+/// refracting through another
+fn rough_dielectric_pdf(facets: Microfacet, ior: f64, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+    let (wo, side) = above(wo);
+    let wi = wi * side;
+    let eta = if side > 0. { ior } else { 1. / ior };
+    let reflected = reflection_pdf(facets, wo, wi, |cos| fresnel(cos, eta));
+    let h = (wo + wi * eta).normalize();
+    let h = h * h[2].signum();
+    let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+    if cos_o <= 0. || cos_i >= 0. {
+        return reflected;
+    }
+    // from the density of facets to that of directions
+    let jacobian = eta * eta * -cos_i / (cos_o + eta * cos_i).powi(2);
+    reflected + facets.pdf(wo, h) * (1. - fresnel(cos_o, eta)) * jacobian
+}
+
+impl Material {
+    /// Whether the material sends light in lobes narrow enough that path
+    /// perturbations follow it through them, and photon mapping looks past
+    /// it rather than gathering photons there. Rough glass always counts,
+    /// since its photons can't be gathered from just one side.
+    pub fn glossy(&self) -> bool {
+        match self {
+            Self::Diffuse(_) | Self::Emissive(..) => false,
+            Self::Dielectric { .. } | Self::RoughDielectric { .. } => true,
+            &Self::Specular(_, alpha) => alpha >= GLOSSY_EXPONENT,
+            &Self::Conductor { roughness, .. } => roughness <= GLOSSY_ROUGHNESS,
+            Self::Combined(mats) => mats.iter().any(|(_, m)| m.glossy()),
+        }
+    }
+    /// The index of refraction of a material that only sends light along
+    /// exact directions -- the mirror and refracted ones -- which nothing but
+    /// sampling it can find, or `None` for any other material
+    pub fn specular(&self) -> Option<f64> {
+        match self {
+            &Self::Dielectric { ior, .. } => Some(ior),
+            _ => None,
+        }
+    }
+    /// Whether light can pass through the material, so that it's lit and
+    /// seen from either side of the surface
+    pub fn transmits(&self) -> bool {
+        matches!(self, Self::Dielectric { .. } | Self::RoughDielectric { .. })
+    }
+    /// Whether it matters which side of the surface is its front: for going
+    /// into a dielectric rather than out of it, or for the side an emissive
+    /// surface glows from
+    pub fn sided(&self) -> bool {
+        matches!(
+            self,
+            Self::Dielectric { .. } | Self::RoughDielectric { .. } | Self::Emissive(..)
         )
     }
 }
+
+/// Sample a direction from a Phong lobe around `axis`, with density
+/// proportional to the cosine to the axis raised to `exponent`
+fn sample_lobe<R: Rng + ?Sized>(axis: Vector3<f64>, exponent: f64, rng: &mut R) -> Vector3<f64> {
+    let z = rng.gen::<f64>().powf(1. / (exponent + 1.));
+    around(axis, z, rng.gen_range(0. ..TAU))
+}
+
+/// Density per unit solid angle of `sample_lobe` choosing `dir`
+fn lobe_pdf(axis: Vector3<f64>, exponent: f64, dir: Vector3<f64>) -> f64 {
+    let cos = axis.normalize().dot(&dir.normalize());
+    if cos <= 0. {
+        0.
+    } else {
+        (exponent + 1.) / TAU * cos.powf(exponent)
+    }
+}
+
+/// Scene files write specular materials as `{ color = [r, g, b], exponent = n }`
+/// rather than as a bare pair.
+fn phong<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(Color, f64), D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Phong {
+        color: Color,
+        exponent: f64,
+    }
+    let Phong { color, exponent } = Phong::deserialize(deserializer)?;
+    Ok((color, exponent))
+}
+
+/// Dielectrics can't be part of a combined material, whose parts all have to
+/// reflect light off the same side of the surface
+fn combined<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(f64, Material)>, D::Error> {
+    let mats = Vec::<(f64, Material)>::deserialize(deserializer)?;
+    if mats.iter().any(|(_, m)| m.transmits()) {
+        return Err(D::Error::custom(
+            "a dielectric can't be part of a combined material",
+        ));
+    }
+    Ok(mats)
+}
+
+/// Scene files write emissive materials as `{ color = [r, g, b], strength = s }`
+fn emission<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(Color, f64), D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Emission {
+        color: Color,
+        strength: f64,
+    }
+    let Emission { color, strength } = Emission::deserialize(deserializer)?;
+    Ok((color, strength))
+}
+
+/// A perfectly smooth surface would be a mirror, which rough materials can
+/// only come close to, so a roughness has to be positive
+fn roughness<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let roughness = f64::deserialize(deserializer)?;
+    if roughness > 0. {
+        Ok(roughness)
+    } else {
+        Err(D::Error::custom("a roughness has to be more than 0"))
+    }
+}
+
+fn white() -> Color {
+    Color::new(1., 1., 1.)
+}
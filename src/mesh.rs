@@ -0,0 +1,295 @@
+use std::convert::TryFrom;
+
+use nalgebra::{Vector2, Vector3};
+use serde::Deserialize;
+
+use crate::vector::Ray;
+use crate::MIN_DIST;
+
+/// An indexed triangle mesh. Normals and UVs are optional, but when present
+/// there is one per vertex.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "MeshData")]
+pub struct Mesh {
+    positions: Vec<Vector3<f64>>,
+    normals: Vec<Vector3<f64>>,
+    uvs: Vec<Vector2<f64>>,
+    triangles: Vec<[usize; 3]>,
+}
+
+/// Where a ray hit a mesh: which triangle, and the barycentric coordinates of
+/// the hit relative to each of its corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshHit {
+    pub t: f64,
+    pub triangle: usize,
+    pub barycentric: Vector3<f64>,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Vector3<f64>>,
+        normals: Vec<Vector3<f64>>,
+        uvs: Vec<Vector2<f64>>,
+        triangles: Vec<[usize; 3]>,
+    ) -> Result<Self, String> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} positions but {} normals",
+                positions.len(),
+                normals.len()
+            ));
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} positions but {} uvs",
+                positions.len(),
+                uvs.len()
+            ));
+        }
+        if let Some(i) = triangles.iter().flatten().find(|&&i| i >= positions.len()) {
+            return Err(format!(
+                "vertex index {} out of range for {} positions",
+                i,
+                positions.len()
+            ));
+        }
+        Ok(Mesh {
+            positions,
+            normals: normals.into_iter().map(|n| n.normalize()).collect(),
+            uvs,
+            triangles,
+        })
+    }
+    /// A copy of the mesh scaled up about the origin, then moved by `offset`
+    pub fn transformed(&self, scale: f64, offset: Vector3<f64>) -> Mesh {
+        Mesh {
+            positions: self.positions.iter().map(|p| p * scale + offset).collect(),
+            ..self.clone()
+        }
+    }
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+    /// The corners of a triangle
+    pub fn vertices(&self, triangle: usize) -> [Vector3<f64>; 3] {
+        let [a, b, c] = self.triangles[triangle];
+        [self.positions[a], self.positions[b], self.positions[c]]
+    }
+    pub fn cast(&self, ray: Ray<f64>) -> Option<MeshHit> {
+        let mut closest: Option<MeshHit> = None;
+        for triangle in 0..self.triangles.len() {
+            if let Some(hit) = self.cast_triangle(triangle, ray) {
+                if !matches!(closest, Some(c) if c.t <= hit.t) {
+                    closest = Some(hit);
+                }
+            }
+        }
+        closest
+    }
+    pub fn cast_triangle(&self, triangle: usize, ray: Ray<f64>) -> Option<MeshHit> {
+        let (t, barycentric) = intersect_triangle(ray, self.vertices(triangle))?;
+        Some(MeshHit {
+            t,
+            triangle,
+            barycentric,
+        })
+    }
+    /// Shading normal at a hit, facing back towards the ray. Without vertex
+    /// normals this is just the face normal.
+    pub fn normal(&self, hit: &MeshHit, ray: Ray<f64>) -> Vector3<f64> {
+        let geometric = face_normal(self.vertices(hit.triangle));
+        let facing = if geometric.dot(&ray.dir) > 0. {
+            -1.
+        } else {
+            1.
+        };
+        if self.normals.is_empty() {
+            return facing * geometric;
+        }
+        let [a, b, c] = self.triangles[hit.triangle];
+        let w = hit.barycentric;
+        let shading = w[0] * self.normals[a] + w[1] * self.normals[b] + w[2] * self.normals[c];
+        // keep the interpolated normal on the same side as the surface
+        facing * shading.normalize() * shading.dot(&geometric).signum()
+    }
+    /// Texture coordinates at a hit, if the mesh has any
+    #[allow(dead_code)] // nothing is textured yet
+    pub fn uv(&self, hit: &MeshHit) -> Option<Vector2<f64>> {
+        if self.uvs.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.triangles[hit.triangle];
+        let w = hit.barycentric;
+        Some(w[0] * self.uvs[a] + w[1] * self.uvs[b] + w[2] * self.uvs[c])
+    }
+}
+
+pub fn face_normal([a, b, c]: [Vector3<f64>; 3]) -> Vector3<f64> {
+    (b - a).cross(&(c - a)).normalize()
+}
+
+/// Watertight ray/triangle intersection (Woop, Benthin and Wald 2013). The
+/// triangle is sheared into a space where the ray points down the z axis, so
+/// that the edge tests for neighbouring triangles are computed identically
+/// and rays can't slip through shared edges. Returns the distance along the
+/// ray and the barycentric coordinates of the hit.
+pub fn intersect_triangle(
+    ray: Ray<f64>,
+    vertices: [Vector3<f64>; 3],
+) -> Option<(f64, Vector3<f64>)> {
+    let dir = ray.dir;
+    let kz = dir.iamax();
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    // preserve the winding direction
+    if dir[kz] < 0. {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = 1. / dir[kz];
+
+    let [a, b, c] = vertices;
+    let (a, b, c) = (a - ray.start, b - ray.start, c - ray.start);
+    let shear = |p: Vector3<f64>| (p[kx] - sx * p[kz], p[ky] - sy * p[kz], sz * p[kz]);
+    let (ax, ay, az) = shear(a);
+    let (bx, by, bz) = shear(b);
+    let (cx, cy, cz) = shear(c);
+
+    // scaled barycentric coordinates, from the signed edge functions
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0. {
+        return None;
+    }
+    let t = (u * az + v * bz + w * cz) / det;
+    if t > MIN_DIST {
+        Some((t, Vector3::new(u, v, w) / det))
+    } else {
+        None
+    }
+}
+
+/// How a mesh is written in a scene file, checked before it becomes a `Mesh`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshData {
+    positions: Vec<Vector3<f64>>,
+    #[serde(default)]
+    normals: Vec<Vector3<f64>>,
+    #[serde(default)]
+    uvs: Vec<Vector2<f64>>,
+    triangles: Vec<[usize; 3]>,
+}
+
+impl TryFrom<MeshData> for Mesh {
+    type Error = String;
+    fn try_from(data: MeshData) -> Result<Self, String> {
+        Mesh::new(data.positions, data.normals, data.uvs, data.triangles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use rand::Rng;
+
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn quad() -> Mesh {
+        Mesh::new(
+            vec![
+                Vector3::new(-1., -1., 0.),
+                Vector3::new(1., -1., 0.),
+                Vector3::new(1., 1., 0.),
+                Vector3::new(-1., 1., 0.),
+            ],
+            vec![
+                Vector3::new(-1., 0., 1.),
+                Vector3::new(1., 0., 1.),
+                Vector3::new(1., 0., 1.),
+                Vector3::new(-1., 0., 1.),
+            ],
+            vec![
+                Vector2::new(0., 0.),
+                Vector2::new(1., 0.),
+                Vector2::new(1., 1.),
+                Vector2::new(0., 1.),
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn triangle_hit() {
+        let tri = [
+            Vector3::new(0., 0., 1.),
+            Vector3::new(1., 0., 1.),
+            Vector3::new(0., 1., 1.),
+        ];
+        let (t, w) =
+            intersect_triangle(Ray::new(Vector3::new(0.25, 0.25, -1.), Vector3::z()), tri).unwrap();
+        assert_abs_diff_eq!(t, 2., epsilon = 1e-12);
+        assert_abs_diff_eq!(w, Vector3::new(0.5, 0.25, 0.25), epsilon = 1e-12);
+        assert!(
+            intersect_triangle(Ray::new(Vector3::new(0.75, 0.75, -1.), Vector3::z()), tri)
+                .is_none()
+        );
+        // behind the ray
+        assert!(
+            intersect_triangle(Ray::new(Vector3::new(0.25, 0.25, 2.), Vector3::z()), tri).is_none()
+        );
+    }
+
+    #[test]
+    fn watertight() {
+        // rays aimed exactly along the shared diagonal must hit one of the
+        // two triangles
+        let mesh = quad();
+        let rng = &mut StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let s: f64 = rng.gen_range(-1. ..1.);
+            let start = Vector3::new(rng.gen_range(-2. ..2.), rng.gen_range(-2. ..2.), -3.);
+            let ray = Ray::new(start, Vector3::new(s, s, 0.) - start);
+            assert!(mesh.cast(ray).is_some(), "{:?}", ray);
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        let mesh = quad();
+        let ray = Ray::new(Vector3::new(0.5, -0.5, -1.), Vector3::z());
+        let hit = mesh.cast(ray).unwrap();
+        assert_abs_diff_eq!(hit.t, 1., epsilon = 1e-12);
+        assert_abs_diff_eq!(
+            mesh.uv(&hit).unwrap(),
+            Vector2::new(0.75, 0.25),
+            epsilon = 1e-12
+        );
+        // the normals lean outwards, and get flipped to face the ray
+        let normal = mesh.normal(&hit, ray);
+        assert!(normal[0] < 0. && normal[2] < 0.);
+        assert_abs_diff_eq!(normal.norm(), 1., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn bad_indices() {
+        assert!(Mesh::new(vec![Vector3::zeros(); 2], vec![], vec![], vec![[0, 1, 2]]).is_err());
+        assert!(Mesh::new(
+            vec![Vector3::zeros(); 3],
+            vec![Vector3::z()],
+            vec![],
+            vec![[0, 1, 2]]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Rough surfaces, made up of tiny mirror facets whose normals are spread out
+//! around the surface normal. Like `bsdf`, this works in the local shading
+//! frame, with the normal along +z. Facets hide each other as in Smith's
+//! model, and are sampled in proportion to how much of them can be seen from
+//! a direction: by Heitz's (2018) method for GGX, and by Heitz and d'Eon's
+//! (2014) for Beckmann.
+
+use std::f64::consts::{PI, TAU};
+
+use nalgebra::Vector3;
+use rand::Rng;
+use serde::Deserialize;
+
+/// The least roughness used, since the distributions turn into delta
+/// functions at 0, which only a `Dielectric` handles
+const MIN_ALPHA: f64 = 1e-3;
+
+/// How the slopes of the facets are distributed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    /// Trowbridge-Reitz, with long tails that give highlights a glow
+    #[default]
+    Ggx,
+    /// Gaussian slopes, with tighter highlights
+    Beckmann,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Microfacet {
+    distribution: Distribution,
+    /// The width of the distribution of slopes, which scene files give as
+    /// the roughness
+    alpha: f64,
+}
+
+impl Microfacet {
+    pub fn new(distribution: Distribution, roughness: f64) -> Self {
+        Microfacet {
+            distribution,
+            alpha: roughness.max(MIN_ALPHA),
+        }
+    }
+    /// Density of facet normals per unit solid angle, weighted by how much
+    /// area of the surface they cover
+    pub fn d(&self, m: Vector3<f64>) -> f64 {
+        if m[2] <= 0. {
+            return 0.;
+        }
+        let cos2 = m[2] * m[2];
+        let tan2 = (1. - cos2) / cos2;
+        let a2 = self.alpha * self.alpha;
+        match self.distribution {
+            Distribution::Ggx => a2 / (PI * cos2 * cos2 * (a2 + tan2).powi(2)),
+            Distribution::Beckmann => (-tan2 / a2).exp() / (PI * a2 * cos2 * cos2),
+        }
+    }
+    /// Smith's Λ: the area of facets hidden from `w` for every unit of area
+    /// that can be seen
+    fn lambda(&self, w: Vector3<f64>) -> f64 {
+        let cos2 = w[2] * w[2];
+        if cos2 >= 1. {
+            return 0.;
+        }
+        let tan2 = (1. - cos2) / cos2;
+        match self.distribution {
+            Distribution::Ggx => ((1. + self.alpha * self.alpha * tan2).sqrt() - 1.) / 2.,
+            Distribution::Beckmann => {
+                let a = 1. / (self.alpha * tan2.sqrt());
+                (erf(a) - 1.) / 2. + (-a * a).exp() / (2. * a * PI.sqrt())
+            }
+        }
+    }
+    /// The fraction of the facets facing `w` that can be seen from it
+    pub fn g1(&self, w: Vector3<f64>) -> f64 {
+        1. / (1. + self.lambda(w))
+    }
+    /// The fraction of the facets that can be seen from both `wi` and `wo`,
+    /// counting the ones that are hidden from both only once
+    pub fn g(&self, wi: Vector3<f64>, wo: Vector3<f64>) -> f64 {
+        1. / (1. + self.lambda(wi) + self.lambda(wo))
+    }
+    /// Pick a facet normal in proportion to how much of it can be seen from
+    /// `w`, which has to be above the surface
+    pub fn sample<R: Rng + ?Sized>(&self, w: Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+        let (u1, u2): (f64, f64) = (rng.gen(), rng.gen());
+        let a = self.alpha;
+        // stretch the surface so that the facets become a unit distribution
+        let stretched = Vector3::new(a * w[0], a * w[1], w[2]).normalize();
+        let m = match self.distribution {
+            Distribution::Ggx => {
+                // pick a point on the half of the disk facing `w`, projected
+                // onto a hemisphere
+                let len = stretched[0].hypot(stretched[1]);
+                let t1 = if len > 0. {
+                    Vector3::new(-stretched[1], stretched[0], 0.) / len
+                } else {
+                    Vector3::x()
+                };
+                let t2 = stretched.cross(&t1);
+                let r = u1.sqrt();
+                let phi = TAU * u2;
+                let x = r * phi.cos();
+                let s = (1. + stretched[2]) / 2.;
+                let y = (1. - s) * (1. - x * x).sqrt() + s * r * phi.sin();
+                let z = (1. - x * x - y * y).max(0.).sqrt();
+                let n = t1 * x + t2 * y + stretched * z;
+                Vector3::new(a * n[0], a * n[1], n[2].max(0.))
+            }
+            Distribution::Beckmann => {
+                // pick the slope seen head on, then turn it to face `w`
+                let (x, y) = beckmann_slopes(stretched[2], u1, u2);
+                let len = stretched[0].hypot(stretched[1]);
+                let (cos, sin) = if len > 0. {
+                    (stretched[0] / len, stretched[1] / len)
+                } else {
+                    (1., 0.)
+                };
+                let (x, y) = (cos * x - sin * y, sin * x + cos * y);
+                Vector3::new(-a * x, -a * y, 1.)
+            }
+        };
+        m.normalize()
+    }
+    /// Density per unit solid angle of `sample` picking `m` for `w`
+    pub fn pdf(&self, w: Vector3<f64>, m: Vector3<f64>) -> f64 {
+        if w[2] <= 0. {
+            return 0.;
+        }
+        self.g1(w) * w.dot(&m).max(0.) * self.d(m) / w[2]
+    }
+}
+
+/// Slopes of a facet seen from an angle with cosine `cos` to the normal of a
+/// Beckmann surface with roughness 1, facing along +x, by inverting the
+/// distribution of their x slopes by Newton's method
+fn beckmann_slopes(cos: f64, u1: f64, u2: f64) -> (f64, f64) {
+    let u1 = u1.max(1e-6);
+    let y = erf_inv(2. * u2.max(1e-6) - 1.);
+    if cos > 0.9999 {
+        // seen head on, where every direction is the same
+        let r = (-(1. - u1).ln()).sqrt();
+        let phi = TAU * u2;
+        return (r * phi.cos(), r * phi.sin());
+    }
+    let sin = (1. - cos * cos).max(0.).sqrt();
+    let tan = sin / cos;
+    let cot = 1. / tan;
+    let normalization = 1. / (1. + erf(cot) + tan * (-cot * cot).exp() / PI.sqrt());
+    // bracket the root, starting from a fit to it
+    let (mut low, mut high) = (-1., erf(cot));
+    let theta = cos.acos();
+    let fit = 1. + theta * (-0.876 + theta * (0.4265 - 0.0594 * theta));
+    let mut b = high - (1. + high) * (1. - u1).powf(fit);
+    for _ in 0..10 {
+        if !(low..=high).contains(&b) {
+            b = (low + high) / 2.;
+        }
+        let inv = erf_inv(b);
+        let value = normalization * (1. + b + tan * (-inv * inv).exp() / PI.sqrt()) - u1;
+        if value.abs() < 1e-9 {
+            break;
+        }
+        if value > 0. {
+            high = b;
+        } else {
+            low = b;
+        }
+        b -= value / (normalization * (1. - inv * tan));
+    }
+    (erf_inv(b), y)
+}
+
+/// The error function, to within about 1e-7 (Abramowitz and Stegun 7.1.26)
+fn erf(x: f64) -> f64 {
+    let t = 1. / (1. + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1. - poly * (-x * x).exp()).copysign(x)
+}
+
+/// The inverse of `erf`, by Giles's (2010) approximation, polished with a
+/// couple of Newton steps
+fn erf_inv(x: f64) -> f64 {
+    let x = x.clamp(-1. + 1e-12, 1. - 1e-12);
+    let w = -((1. - x) * (1. + x)).ln();
+    let mut y = if w < 5. {
+        let w = w - 2.5;
+        let p = [
+            2.81022636e-08,
+            3.43273939e-07,
+            -3.5233877e-06,
+            -4.39150654e-06,
+            0.00021858087,
+            -0.00125372503,
+            -0.00417768164,
+            0.246640727,
+            1.50140941,
+        ];
+        p.iter().fold(0., |acc, c| acc * w + c) * x
+    } else {
+        let w = w.sqrt() - 3.;
+        let p = [
+            -0.000200214257,
+            0.000100950558,
+            0.00134934322,
+            -0.00367342844,
+            0.00573950773,
+            -0.0076224613,
+            0.00943887047,
+            1.00167406,
+            2.83297682,
+        ];
+        p.iter().fold(0., |acc, c| acc * w + c) * x
+    };
+    for _ in 0..2 {
+        y -= (erf(y) - x) / (2. / PI.sqrt() * (-y * y).exp());
+    }
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn visible_normals_match_their_density() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        for distribution in [Distribution::Ggx, Distribution::Beckmann] {
+            let facets = Microfacet::new(distribution, 0.4);
+            // the projected area of the facets covers the surface exactly,
+            // from any direction
+            for w in [Vector3::z(), Vector3::new(0.6, 0.2, 0.5).normalize()] {
+                let n = 200_000;
+                let total: f64 = (0..n)
+                    .map(|_| {
+                        let z: f64 = rng.gen();
+                        let m = crate::vector::spherical(z, rng.gen_range(0. ..TAU));
+                        facets.pdf(w, m) * TAU
+                    })
+                    .sum::<f64>()
+                    / n as f64;
+                assert!((total - 1.).abs() < 0.03, "{:?}: {}", distribution, total);
+
+                // and the samples land where the density says: compare how
+                // many point within 30 degrees of the normal
+                let inside = (0..n)
+                    .filter(|_| facets.sample(w, rng)[2] > 30f64.to_radians().cos())
+                    .count() as f64
+                    / n as f64;
+                let expected: f64 = (0..n)
+                    .map(|_| {
+                        let z = 1. - rng.gen::<f64>() * (1. - 30f64.to_radians().cos());
+                        let m = crate::vector::spherical(z, rng.gen_range(0. ..TAU));
+                        facets.pdf(w, m) * TAU * (1. - 30f64.to_radians().cos())
+                    })
+                    .sum::<f64>()
+                    / n as f64;
+                assert!(
+                    (inside - expected).abs() < 0.01,
+                    "{:?}: {} vs {}",
+                    distribution,
+                    inside,
+                    expected
+                );
+            }
+        }
+    }
+}
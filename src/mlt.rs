@@ -1,298 +1,782 @@
-use std::f64::consts::PI;
+use std::f64::consts::TAU;
 
+use crate::bootstrap::{self, Bootstrap};
+use crate::bsdf::Bsdf;
 use crate::camera::{Camera, ImageBuffer};
-use crate::scene::{Light, Object, Scene};
-use crate::vector::Ray;
-use crate::{CONTINUE_CHANCE, DISTANCE_FACTOR};
+use crate::scene::{Light, LightVertex, Object, Scene};
+use crate::settings::RenderSettings;
+use crate::vector::{around, reflect, refract, Ray};
 use nalgebra::Vector3;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::{self, Rng};
 
-pub fn draw(n: usize, x: f64, y: f64, light: &Light, scene: &Scene, image: &ImageBuffer) {
-    let mut rng = rand::thread_rng();
-    // // Choose a path by bidirectional path tracing
-    let (p0, mut path) = scene.propose(x, y, light, &mut rng);
-    let mut old_p = p0;
+/// Most vertices a bidirectional mutation deletes, or adds back, at once
+const MAX_CHANGE: usize = 2;
+/// Smallest and largest angle, in radians, that a perturbation turns a
+/// direction leaving a diffuse surface by
+const PERTURBATION_ANGLE: (f64, f64) = (1e-4, 0.1);
+
+pub fn draw<'a, R: Rng + ?Sized>(
+    n: usize,
+    seeds: &Bootstrap<Path<'a>>,
+    scene: &'a Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let mut path = match seeds.seed(rng) {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let mut value = path.measure(scene, settings);
+    // the chain visits paths in proportion to their value, so each sample is
+    // scaled by b over its value to recover the light it carries
+    let weight = seeds.b / (n as f64 * settings.film_area());
     for _ in 0..n {
-        scene.camera.record_sample(
-            &path,
-            scene,
-            image,
-            10. / scene.lights.len() as f64 / n as f64,
-        );
-        if let Some((p, new_path)) = path.mutate(scene, &mut rng) {
-            let measure = path.measure(scene);
-            if measure == 0. {
-                // accept unconditionally, old path was blocked
-                // println!(".");
-                old_p = p;
-                path = new_path;
-            } else {
-                let accept = new_path.measure(scene) / measure * old_p / p;
-            //     if accept.is_nan() {
-            //     println!("{}", accept);
-            // }
-                if rng.gen::<f64>() < accept {
-                    // println!(".");
-                    old_p = p;
-                    path = new_path;
-                }
-            }
+        scene
+            .camera
+            .record_sample(&path, scene, image, settings, weight / value);
+        let (next, next_value) = step(path, value, scene, settings, rng);
+        path = next;
+        value = next_value;
+    }
+}
+
+/// Sample independent paths to estimate the integral of `Path::measure` over
+/// all paths, and to seed the chains with
+pub fn bootstrap<'a, R: Rng + ?Sized>(
+    scene: &'a Scene,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Bootstrap<Path<'a>> {
+    Bootstrap::new(settings.bootstrap_samples, || {
+        let (density, path) = scene.propose(settings, rng)?;
+        Some((path.measure(scene, settings) / density, path))
+    })
+}
+
+/// Estimate the integral of `Path::measure` over all paths like `bootstrap`,
+/// without keeping any of the paths
+pub fn estimate_b<R: Rng + ?Sized>(scene: &Scene, settings: &RenderSettings, rng: &mut R) -> f64 {
+    bootstrap::estimate_b(settings.bootstrap_samples, || {
+        let (density, path) = scene.propose(settings, rng)?;
+        Some(path.measure(scene, settings) / density)
+    })
+}
+
+/// One Metropolis-Hastings step away from `path`, whose target value is
+/// `value`. A mutation from x to y is accepted with probability
+/// min(1, f(y) T(y -> x) / f(x) T(x -> y)), which leaves the distribution of
+/// paths proportional to `measure`.
+pub fn step<'a, R: Rng + ?Sized>(
+    path: Path<'a>,
+    value: f64,
+    scene: &'a Scene,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> (Path<'a>, f64) {
+    if let Some((new_path, forward, reverse)) = path.mutate(scene, settings, rng) {
+        let new_value = new_path.measure(scene, settings);
+        let accept = if forward == 0. {
+            // the mutation had no chance of proposing this path, so the ratio
+            // means nothing
+            0.
+        } else if value == 0. {
+            // accept unconditionally, old path was blocked
+            1.
+        } else {
+            new_value * reverse / (value * forward)
+        };
+        if rng.gen::<f64>() < accept {
+            return (new_path, new_value);
         }
     }
+    (path, value)
 }
 
 #[derive(Debug, Clone)]
 pub struct Path<'a> {
     // order is from light
     pub light: &'a Light,
+    /// The light's surface normal at the first point (zero for point lights)
+    pub light_normal: Vector3<f64>,
     pub objects: Vec<&'a Object>,
     pub camera: &'a Camera,
-    // first element is point, second is normal at that point
+    // the light and camera are the first and last points; the normals and
+    // objects belong to the surface points in between
     pub points: Vec<Vector3<f64>>,
     pub normals: Vec<Vector3<f64>>,
 }
 
+/// A surface vertex that isn't part of a path yet
+pub type Vertex<'a> = (Vector3<f64>, Vector3<f64>, &'a Object);
+
 impl<'a> Path<'a> {
-    // similar to camera work (should be deduplicated)
-    fn measure(&self, scene: &Scene) -> f64 {
-        // light pdf
-        let mut prob = 1. / (4. * PI);
-        if prob.is_nan() {
-            println!("nan at start somehow");
+    /// Join a subpath traced out from the light to one traced out from the
+    /// camera, each given in the order they were traced
+    pub fn connect(
+        (start, light_normal, light): LightVertex<'a>,
+        light_side: &[Vertex<'a>],
+        camera_side: &[Vertex<'a>],
+        camera: &'a Camera,
+    ) -> Self {
+        let mut path = Path {
+            light,
+            light_normal,
+            objects: vec![],
+            camera,
+            points: vec![start],
+            normals: vec![],
+        };
+        for &(point, normal, object) in light_side.iter().chain(camera_side.iter().rev()) {
+            path.points.push(point);
+            path.normals.push(normal);
+            path.objects.push(object);
         }
-        for i in 0..self.objects.len() {
-            let x0 = self.points[i];
-            let x1 = self.points[i + 1];
-            let x2 = self.points[i + 2];
-            let incoming = x0 - x1;
-            let outgoing = x2 - x1;
-            let normal = self.normals[i];
-            // check occlusion
-            if let Some((t, _, _)) = scene.cast(Ray::new(x1, incoming)) {
-                if t < 1. {
-                    prob = 0.;
-                    break;
-                }
+        path.points.push(camera.pos);
+        path.aim_light();
+        path
+    }
+    /// Light from a distant light can only have come straight back along its
+    /// direction, so move the point on it there
+    fn aim_light(&mut self) {
+        if self.light.is_distant() && self.points.len() > 2 {
+            self.points[0] = self.light.behind(self.points[1], self.light_normal);
+        }
+    }
+    /// The target function the chains are proportional to: the luminance of
+    /// the light the path brings to the camera
+    pub fn measure(&self, scene: &Scene, settings: &RenderSettings) -> f64 {
+        match self.camera.contribution(self, scene, settings) {
+            Some((_, color)) => color.luminance(),
+            None => 0.,
+        }
+    }
+    /// Index of the camera point
+    pub fn last(&self) -> usize {
+        self.points.len() - 1
+    }
+    /// Density per unit solid angle of sampling the direction from point
+    /// `from` towards point `to`
+    fn direction_pdf(&self, from: usize, to: usize, settings: &RenderSettings) -> f64 {
+        let dir = self.points[to] - self.points[from];
+        if from == 0 {
+            self.light.pdf(self.light_normal, dir)
+        } else if from == self.last() {
+            self.camera.pdf(dir, settings)
+        } else {
+            // the point on the other side of `from`, which the walk came from
+            let back = self.points[2 * from - to] - self.points[from];
+            self.objects[from - 1]
+                .material
+                .at(self.normals[from - 1])
+                .pdf(dir, back)
+        }
+    }
+    /// Density per unit area of finding surface point `to` by sampling a
+    /// direction at point `from` and casting a ray
+    fn area_pdf(&self, from: usize, to: usize, settings: &RenderSettings) -> f64 {
+        if from == 0 {
+            // a distant light's light comes in as a beam, with no angle to it
+            let (point, normal) = (self.points[to], self.normals[to - 1]);
+            if let Some(pdf) = self.light.beam_pdf(self.light_normal, point, normal) {
+                return pdf;
             }
-            let mut geom = 1.; // (1. + DISTANCE_FACTOR * incoming.magnitude_squared());
-            geom *= incoming.normalize().dot(&normal);
-            prob *= geom;
-            // if prob.is_nan() {
-            //     panic!("nan at geometry\nx0 = {}\nx1 = {} geom= {}\nincoming = {}\nnormal={}\npath={:#?}", x0, x1, geom, incoming, normal, self);
-            // }
+        }
+        self.direction_pdf(from, to, settings) * self.area_factor(from, to)
+    }
+    /// Converts a density per unit solid angle at point `from` into one per
+    /// unit area at point `to`
+    fn area_factor(&self, from: usize, to: usize) -> f64 {
+        let dir = self.points[to] - self.points[from];
+        dir.normalize().dot(&self.normals[to - 1]).abs() / dir.norm_squared()
+    }
+    /// Whether point `i` is glossy, rather than diffuse or one of the ends
+    /// of the path
+    fn glossy(&self, i: usize) -> bool {
+        i != 0 && i != self.last() && self.objects[i - 1].material.glossy()
+    }
+    /// The index of refraction at point `i` if it is specular, or `None` if it
+    /// isn't or is one of the ends of the path
+    fn specular(&self, i: usize) -> Option<f64> {
+        if i == 0 || i == self.last() {
+            None
+        } else {
+            self.objects[i - 1].material.specular()
+        }
+    }
+    /// Density of the points strictly between `l` and `m` being generated by
+    /// walking `split` of them out from `l`, and the rest back from `m`, then
+    /// connecting the two. A connection can't go through a specular point,
+    /// which only sends light one exact way.
+    pub fn split_pdf(&self, l: usize, m: usize, split: usize, settings: &RenderSettings) -> f64 {
+        let joint = l + split;
+        if self.specular(joint).is_some() || self.specular(joint + 1).is_some() {
+            return 0.;
+        }
+        self.walk_pdf(l, m, split, settings)
+    }
+    /// Like `split_pdf`, without the connection
+    fn walk_pdf(&self, l: usize, m: usize, split: usize, settings: &RenderSettings) -> f64 {
+        let light_side: f64 = (l + 1..=l + split)
+            .map(|i| self.area_pdf(i - 1, i, settings))
+            .product();
+        let camera_side: f64 = (l + split + 1..m)
+            .map(|i| self.area_pdf(i + 1, i, settings))
+            .product();
+        light_side * camera_side
+    }
+    /// Density of the light side walking out as far as surface point `i`,
+    /// and the camera side walking back to it as well, which is how vertex
+    /// merging finds a path: the two meet in the same spot, give or take
+    pub fn merge_pdf(&self, i: usize, settings: &RenderSettings) -> f64 {
+        self.walk_pdf(0, self.last(), i - 1, settings) * self.area_pdf(i - 1, i, settings)
+    }
+    /// Density of the points strictly between `l` and `m` being generated by
+    /// walking out from both ends. Any split between the light and camera
+    /// sides could have made them, so the density sums over all of them.
+    fn subpath_pdf(&self, l: usize, m: usize, settings: &RenderSettings) -> f64 {
+        let count = m - l - 1;
+        let total: f64 = (0..=count)
+            .map(|split| self.split_pdf(l, m, split, settings))
+            .sum();
+        total / (count + 1) as f64
+    }
+    /// Probability of a mutation keeping point `l` and any particular one of
+    /// the points after it, deleting the ones in between
+    fn deletion_probability(&self, l: usize) -> f64 {
+        let last = self.last();
+        1. / last as f64 / (last.min(l + 1 + MAX_CHANGE) - l) as f64
+    }
+    /// Mutate the path with one of the strategies, picked according to their
+    /// weights in `settings`. Returns the new path along with the densities of
+    /// mutating this path into it and back again, or `None` if the mutation
+    /// failed or doesn't apply to this path. Each strategy is reversible on
+    /// its own, and is picked with the same probability whatever the path, so
+    /// those probabilities cancel out of the acceptance ratio.
+    fn mutate<R: Rng + ?Sized>(
+        &self,
+        scene: &'a Scene,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Option<(Path<'a>, f64, f64)> {
+        let weights = [
+            settings.bidirectional_weight,
+            settings.lens_weight,
+            settings.caustic_weight,
+            settings.multi_chain_weight,
+        ];
+        // with every weight at zero, fall back to bidirectional mutations
+        let strategy = WeightedIndex::new(weights).map_or(0, |w| w.sample(rng));
+        let last = self.last();
+        match strategy {
+            0 => self.bidirectional_mutation(scene, settings, rng),
+            1 => self.perturbation(last, self.lens_end()?, scene, settings, rng),
+            2 => self.perturbation(self.caustic_start()?, last - 1, scene, settings, rng),
+            _ => self.perturbation(last, self.multi_chain_end()?, scene, settings, rng),
+        }
+    }
+    /// Bidirectional mutation: delete the points between two that are kept,
+    /// and regenerate a new subpath in their place
+    fn bidirectional_mutation<R: Rng + ?Sized>(
+        &self,
+        scene: &'a Scene,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Option<(Path<'a>, f64, f64)> {
+        let last = self.last();
+        let l = rng.gen_range(0..last);
+        let m = rng.gen_range(l + 1..=last.min(l + 1 + MAX_CHANGE));
+        let added = rng.gen_range(0..=MAX_CHANGE);
+        // there has to be at least one surface point left
+        if last + 1 - (m - l - 1) + added < 3 {
+            return None;
+        }
+        let light_len = rng.gen_range(0..=added);
 
-            // BSDF contribution
-            let phi_in = incoming.angle(&normal);
-            let phi_out = outgoing.angle(&normal);
-            // project both onto the plane formed by the
-            // this means that only symmetric distributions are allowed, due to the way it measureswe
-            let proj_in = incoming - incoming.dot(&normal) / normal.magnitude_squared() * normal;
-            let proj_out = outgoing - outgoing.dot(&normal) / normal.magnitude_squared() * normal;
-            let theta = proj_in.angle(&proj_out);
-            prob *= self.objects[i]
+        // starting from the light moves the point on it too
+        let (start, light_normal, dir) = if l == 0 {
+            let (start, normal) = self.light.sample_point(rng);
+            (start, normal, self.light.propose(normal, rng).1)
+        } else {
+            let back = self.points[l - 1] - self.points[l];
+            let dir = self.objects[l - 1]
+                .material
+                .at(self.normals[l - 1])
+                .sample(back, rng)
+                .1;
+            (self.points[l], self.light_normal, dir)
+        };
+        let light_side = walk(scene, start, dir, light_len, rng)?;
+        let dir = if m == last {
+            self.camera.sample(settings, rng)
+        } else {
+            let back = self.points[m + 1] - self.points[m];
+            self.objects[m - 1]
                 .material
-                .bsdf(phi_in, theta, phi_out)
-                .luminance();
+                .at(self.normals[m - 1])
+                .sample(back, rng)
+                .1
+        };
+        let camera_side = walk(scene, self.points[m], dir, added - light_len, rng)?;
 
-            // if prob.is_nan() {
-            //     println!("nan at luminance");
-            // }
+        let new_vertices = light_side.into_iter().chain(camera_side.into_iter().rev());
+        let mut points = self.points[..l].to_vec();
+        points.push(start);
+        let mut normals = self.normals[..l].to_vec();
+        let mut objects = self.objects[..l].to_vec();
+        for (point, normal, object) in new_vertices {
+            points.push(point);
+            normals.push(normal);
+            objects.push(object);
         }
-        prob
-    }
-    fn mutate<R: Rng + ?Sized>(&self, scene: &'a Scene, rng: &mut R) -> Option<(f64, Path<'a>)> {
-        match rng.gen_range(0..1) {
-            // bidirectional mutation: regenerate part of the path
-            0 => {
-                let mut prob = 1.;
-                if self.objects.len() > 0 {
-                    let start = rng.gen_range(0..self.objects.len());
-                    prob *= 1. / self.objects.len() as f64;
-                    let end = rng.gen_range(start..self.objects.len());
-                    prob *= 1. / (self.objects.len() - start) as f64;
-                    let new_light_len = rng.gen_range(0..2);
-                    prob *= 0.5;
-                    let mut new_light = Vec::with_capacity(new_light_len);
-                    let mut new_light_normals = Vec::with_capacity(new_light_len);
-                    let mut new_light_objects = Vec::with_capacity(new_light_len);
-                    for i in 0..new_light_len {
-                        let (x0, normal, obj) = if i == 0 {
-                            (
-                                self.points[start + 1],
-                                self.normals[start],
-                                self.objects[start],
-                            )
-                        } else {
-                            (
-                                new_light[i - 1],
-                                new_light_normals[i - 1],
-                                new_light_objects[i - 1],
-                            )
-                        };
-                        let (p, proposal) = obj.material.propose(normal, rng);
-                        prob *= p;
-                        let ray = Ray::new(x0, proposal);
-                        if let Some((t, n, o)) = scene.cast(ray) {
-                            new_light.push(ray.of(t));
-                            new_light_normals.push(n);
-                            new_light_objects.push(o)
-                        } else {
-                            return None;
-                        }
-                    }
-                    let new_camera_len = rng.gen_range(0..2);
-                    prob *= 0.5;
+        points.extend_from_slice(&self.points[m..]);
+        normals.extend_from_slice(&self.normals[m - 1..]);
+        objects.extend_from_slice(&self.objects[m - 1..]);
+        let mut path = Path {
+            light: self.light,
+            light_normal,
+            objects,
+            camera: self.camera,
+            points,
+            normals,
+        };
+        path.aim_light();
 
-                    let mut new_camera = Vec::with_capacity(new_camera_len);
-                    let mut new_camera_normals = Vec::with_capacity(new_camera_len);
-                    let mut new_camera_objects = Vec::with_capacity(new_camera_len);
-                    for i in 0..new_camera_len {
-                        let (x0, normal, obj) = if i == 0 {
-                            (self.points[end + 1], self.normals[end], self.objects[end])
-                        } else {
-                            (
-                                new_camera[i - 1],
-                                new_camera_normals[i - 1],
-                                new_camera_objects[i - 1],
-                            )
-                        };
-                        let (p, proposal) = obj.material.propose(normal, rng);
-                        prob *= p;
-                        let ray = Ray::new(x0, proposal);
-                        if let Some((t, n, o)) = scene.cast(ray) {
-                            new_camera.push(ray.of(t));
-                            new_camera_normals.push(n);
-                            new_camera_objects.push(o)
-                        } else {
-                            return None;
-                        }
-                    }
-                    let mut points = self.points[..=start + 1].to_owned();
-                    points.extend(new_light);
-                    points.extend(new_camera.into_iter().rev());
-                    points.extend(self.points[end + 1..].iter().map(|p| *p));
-                    let mut normals = self.normals[..=start].to_owned();
-                    normals.extend(new_light_normals);
-                    normals.extend(new_camera_normals.into_iter().rev());
-                    normals.extend(self.normals[end..].iter().map(|p| *p));
-                    let mut objects = self.objects[..=start].to_owned();
-                    objects.extend(new_light_objects);
-                    objects.extend(new_camera_objects.into_iter().rev());
-                    objects.extend(self.objects[end..].iter().map(|p| *p));
-                    return Some((
-                        prob,
-                        Path {
-                            light: self.light,
-                            objects,
-                            camera: self.camera,
-                            points,
-                            normals,
-                        },
-                    ));
+        // the number of points added is chosen uniformly, so it cancels out
+        let new_m = l + added + 1;
+        let mut forward = self.deletion_probability(l) * path.subpath_pdf(l, new_m, settings);
+        let mut reverse = path.deletion_probability(l) * self.subpath_pdf(l, m, settings);
+        if l == 0 {
+            // which cancels out for lights picked uniformly, but not for the
+            // directions of an environment map
+            forward *= self.light.point_pdf(path.light_normal);
+            reverse *= self.light.point_pdf(self.light_normal);
+        }
+        Some((path, forward, reverse))
+    }
+    /// Where a lens perturbation stops: the first diffuse point seen from the
+    /// camera, possibly by way of glossy reflections
+    fn lens_end(&self) -> Option<usize> {
+        (1..self.last()).rev().find(|&i| !self.glossy(i))
+    }
+    /// Where a multi-chain perturbation stops: past the end of the lens
+    /// perturbation, for as long as each diffuse point is lit by way of glossy
+    /// ones that lead back to another diffuse surface. `None` if that is no
+    /// further than the lens perturbation would go.
+    fn multi_chain_end(&self) -> Option<usize> {
+        let lens_end = self.lens_end()?;
+        let mut end = lens_end;
+        while end > 1 && self.glossy(end - 1) {
+            match (1..end - 1).rev().find(|&i| !self.glossy(i)) {
+                Some(i) => end = i,
+                None => break,
+            }
+        }
+        if end < lens_end {
+            Some(end)
+        } else {
+            None
+        }
+    }
+    /// Where a caustic perturbation starts: the last diffuse point (or the
+    /// light) before any glossy ones leading to the point the camera sees,
+    /// which has to be diffuse itself. Light from a distant light only goes
+    /// one way, so there's no nudging it.
+    fn caustic_start(&self) -> Option<usize> {
+        let last = self.last();
+        if self.glossy(last - 1) {
+            return None;
+        }
+        let start = (0..last - 1).rev().find(|&i| !self.glossy(i))?;
+        if start == 0 && self.light.is_distant() {
+            return None;
+        }
+        Some(start)
+    }
+    /// Perturb the path by tracing from point `from` to point `to`, replacing
+    /// the points after `from` up to and including `to`. The first direction
+    /// comes from nudging the film point or the old direction, and each
+    /// glossy surface along the way is followed by sampling its BSDF. The new
+    /// points have to be glossy or diffuse just like the ones they replace,
+    /// and the rest of the path is kept as it is.
+    fn perturbation<R: Rng + ?Sized>(
+        &self,
+        from: usize,
+        to: usize,
+        scene: &'a Scene,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Option<(Path<'a>, f64, f64)> {
+        let mut path = self.clone();
+        for (prev, i) in trace(from, to) {
+            let dir = if prev == self.last() {
+                let (x, y) = self.camera.project(self.points[i], settings)?;
+                let (min, max) = lens_radii(settings);
+                let r = log_uniform(min, max, rng);
+                let phi = rng.gen_range(0. ..TAU);
+                self.camera.propose(x + r * phi.cos(), y + r * phi.sin())
+            } else if let Some(ior) = path.specular(prev) {
+                // the same way the old path went: reflected or refracted
+                let normal = path.normals[prev - 1];
+                let back = path.points[2 * prev - i] - path.points[prev];
+                let old = self.points[i] - self.points[prev];
+                if old.dot(&normal) * back.dot(&normal) > 0. {
+                    reflect(back, normal)
+                } else {
+                    refract(back, normal, ior)?
                 }
+            } else if path.glossy(prev) {
+                let back = path.points[2 * prev - i] - path.points[prev];
+                let shading = path.objects[prev - 1].material.at(path.normals[prev - 1]);
+                shading.sample(back, rng).1
+            } else {
+                let (min, max) = PERTURBATION_ANGLE;
+                let theta = log_uniform(min, max, rng);
+                let old = (self.points[i] - self.points[prev]).normalize();
+                around(old, theta.cos(), rng.gen_range(0. ..TAU))
+            };
+            let ray = Ray::new(path.points[prev], dir);
+            let (t, normal, object, _) = scene.cast(ray)?;
+            path.points[i] = ray.of(t);
+            path.normals[i - 1] = normal;
+            path.objects[i - 1] = object;
+            if path.glossy(i) != self.glossy(i)
+                || path.specular(i).is_some() != self.specular(i).is_some()
+            {
+                return None;
             }
-            _ => unreachable!(),
         }
-        None
+        path.aim_light();
+        let forward = path.perturbation_pdf(self, from, to, settings);
+        let reverse = self.perturbation_pdf(&path, from, to, settings);
+        Some((path, forward, reverse))
+    }
+    /// Density per unit area of the points after `from` up to `to` being
+    /// found by perturbing `old`
+    fn perturbation_pdf(
+        &self,
+        old: &Path,
+        from: usize,
+        to: usize,
+        settings: &RenderSettings,
+    ) -> f64 {
+        trace(from, to)
+            .map(|(prev, i)| self.perturbation_step_pdf(old, prev, i, settings))
+            .product()
+    }
+    /// Density per unit area of point `to` being found by perturbing `old`
+    /// at point `from`, given the points before it
+    fn perturbation_step_pdf(
+        &self,
+        old: &Path,
+        from: usize,
+        to: usize,
+        settings: &RenderSettings,
+    ) -> f64 {
+        let dir = self.points[to] - self.points[from];
+        let density = if from == self.last() {
+            let project = |path: &Path| path.camera.project(path.points[to], settings);
+            match (project(old), project(self)) {
+                (Some((x0, y0)), Some((x1, y1))) => {
+                    // uniform in angle around the old film point
+                    let r = (x1 - x0).hypot(y1 - y0);
+                    let (min, max) = lens_radii(settings);
+                    let film_pdf = log_uniform_pdf(r, min, max) / (TAU * r);
+                    film_pdf * settings.film_area() * self.camera.pdf(dir, settings)
+                }
+                _ => 0.,
+            }
+        } else if self.specular(from).is_some() {
+            // there was only one way to go
+            1.
+        } else if self.glossy(from) {
+            self.direction_pdf(from, to, settings)
+        } else {
+            let theta = (old.points[to] - old.points[from]).angle(&dir);
+            let (min, max) = PERTURBATION_ANGLE;
+            log_uniform_pdf(theta, min, max) / (TAU * theta.sin())
+        };
+        density * self.area_factor(from, to)
     }
 }
 
+/// Each step of tracing from point `from` to point `to`, as the point the
+/// ray leaves and the point it finds
+fn trace(from: usize, to: usize) -> Box<dyn Iterator<Item = (usize, usize)>> {
+    if from < to {
+        Box::new((from + 1..=to).map(|i| (i - 1, i)))
+    } else {
+        Box::new((to..from).rev().map(|i| (i + 1, i)))
+    }
+}
+
+/// Smallest and largest distance a lens perturbation moves the film point:
+/// from a tenth of a pixel to a tenth of the film's width
+fn lens_radii(settings: &RenderSettings) -> (f64, f64) {
+    let pixel = 2. / settings.width as f64;
+    ((0.1 * pixel).min(0.02), 0.2)
+}
+
+/// Sample between `min` and `max` with density inversely proportional to the
+/// value, so that small and large changes are both tried often
+fn log_uniform<R: Rng + ?Sized>(min: f64, max: f64, rng: &mut R) -> f64 {
+    min * (max / min).powf(rng.gen::<f64>())
+}
+
+fn log_uniform_pdf(x: f64, min: f64, max: f64) -> f64 {
+    if x < min || x > max {
+        0.
+    } else {
+        1. / (x * (max / min).ln())
+    }
+}
+
+/// Cast `count` rays, starting in direction `dir` and then scattering off
+/// each surface that is hit
+pub fn walk<'a, R: Rng + ?Sized>(
+    scene: &'a Scene,
+    mut start: Vector3<f64>,
+    mut dir: Vector3<f64>,
+    count: usize,
+    rng: &mut R,
+) -> Option<Vec<Vertex<'a>>> {
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(count);
+    for _ in 0..count {
+        if let Some(&(point, normal, object)) = vertices.last() {
+            dir = object.material.at(normal).sample(start - point, rng).1;
+            start = point;
+        }
+        let ray = Ray::new(start, dir);
+        let (t, normal, object, _) = scene.cast(ray)?;
+        vertices.push((ray.of(t), normal, object));
+    }
+    Some(vertices)
+}
+
 impl Scene {
-    /// All the propose methods give a path (or ray) and the probability of generating it
+    /// Sample a path independently of any chain: pick a light and a point on
+    /// the film, pick how many surface points to add, going on each time with
+    /// `continue_chance`, then trace some of them out from the camera and the
+    /// rest from the light. Gives the path along with its density per unit
+    /// area of each surface point (and of the film), summed over the ways of
+    /// splitting it, or `None` if a ray leaves the scene or every split meets
+    /// at a specular point.
     pub fn propose<'a, R: Rng + ?Sized>(
         &'a self,
-        x: f64,
-        y: f64,
-        light: &'a Light,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Option<(f64, Path<'a>)> {
+        self.propose_in(None, settings, rng)
+    }
+    /// Like `propose`, but with the film point inside `pixel`, if one is
+    /// given. The density is still given as if the point were picked from
+    /// the whole film, so the same scale works for either.
+    pub fn propose_in<'a, R: Rng + ?Sized>(
+        &'a self,
+        pixel: Option<usize>,
+        settings: &RenderSettings,
         rng: &mut R,
-    ) -> (f64, Path) {
-        // let light = self.lights.choose(rng).unwrap();
-        // this term gets cancelled out anyway
-        let mut prob = 1.; // self.lights.len() as f64;
+    ) -> Option<(f64, Path<'a>)> {
+        let light = self.sample_light(rng)?;
+        let (light_pos, light_normal, emitter) = light;
         let camera = &self.camera;
-        let mut light_points = vec![light.pos];
-        let mut light_objects = vec![];
-        let mut light_normals = vec![];
-        let mut camera_points = vec![camera.pos];
-        let mut camera_objects = vec![];
-        let mut camera_normals = vec![];
-        // cast camera ray
-        let r = camera.propose(x, y);
-        // prob *= p;
-        let ray = Ray::new(camera.pos, r);
-        if let Some((t, n, o)) = self.cast(ray) {
-            camera_points.push(ray.of(t));
-            camera_normals.push(n);
-            camera_objects.push(o);
+        let mut density = self.light_pdf(emitter, light_normal);
+        let mut count = 1;
+        while rng.gen_bool(settings.continue_chance) {
+            density *= settings.continue_chance;
+            count += 1;
+        }
+        density *= 1. - settings.continue_chance;
 
-            if rng.gen_bool(CONTINUE_CHANCE) {
-                prob *= CONTINUE_CHANCE;
-                // cast a ray from the light
-                let (p, r) = light.propose(rng);
-                prob *= p;
-                let ray = Ray::new(light.pos, r);
-                if let Some((t, n, o)) = self.cast(ray) {
-                    light_points.push(ray.of(t));
-                    light_normals.push(n);
-                    light_objects.push(o);
-                    loop {
-                        if !rng.gen_bool(CONTINUE_CHANCE) {
-                            break;
-                        }
-                        prob *= CONTINUE_CHANCE;
+        // split the points between the two sides at random, so that paths
+        // through specular points can be made by connecting somewhere else.
+        // The camera side has to pick the film point when it's kept inside a
+        // pixel, so it can only be left empty otherwise.
+        let splits = if pixel.is_some() { count } else { count + 1 };
+        let split = rng.gen_range(0..splits);
+        let camera_side = match count - split {
+            0 => vec![],
+            n => {
+                let dir = match pixel {
+                    Some(pixel) => camera.sample_pixel(pixel, settings, rng),
+                    None => camera.sample(settings, rng),
+                };
+                walk(self, camera.pos, dir, n, rng)?
+            }
+        };
+        let light_side = match split {
+            0 => vec![],
+            n => {
+                let dir = emitter.propose(light_normal, rng).1;
+                walk(self, light_pos, dir, n, rng)?
+            }
+        };
+
+        let path = Path::connect(light, &light_side, &camera_side, camera);
+        let total: f64 = (0..splits)
+            .map(|split| path.split_pdf(0, path.last(), split, settings))
+            .sum();
+        density *= total / splits as f64;
+        if density == 0. {
+            // every connection would go through a specular point
+            return None;
+        }
+        Some((density, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::scene::Shape;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const LIGHT: [f64; 3] = [0.5, 1., 0.25];
+
+    /// A camera looking straight down at a plane, which it sees out to 2 units
+    /// either side, lit from 1 unit above. Only paths with a single bounce can
+    /// carry light, so their value only depends on the geometry either side
+    /// of the plane.
+    fn plane_scene() -> (Scene, RenderSettings) {
+        let scene = Scene::new(
+            Camera::new(
+                Vector3::new(0., 2., 0.),
+                -Vector3::y(),
+                Vector3::z(),
+                PI / 4.,
+            ),
+            vec![Object {
+                shape: Shape::Plane {
+                    center: Vector3::zeros(),
+                    normal: Vector3::y(),
+                },
+                material: Material::Diffuse(Color::new(0.5, 0.5, 0.5)),
+            }],
+            vec![Light::point(Vector3::from(LIGHT), Color::new(1., 1., 1.))],
+        );
+        let settings = RenderSettings {
+            width: 100,
+            height: 100,
+            bootstrap_samples: 100_000,
+            ..Default::default()
+        };
+        (scene, settings)
+    }
 
-                        // add a new camera point
-                        let (p, r) = camera_objects
-                            .last()
-                            .unwrap()
-                            .material
-                            .propose(*camera_normals.last().unwrap(), rng);
-                        prob *= p;
-                        let ray = Ray::new(*camera_points.last().unwrap(), r);
-                        if let Some((t, n, o)) = self.cast(ray) {
-                            camera_points.push(ray.of(t));
-                            camera_normals.push(n);
-                            camera_objects.push(o);
-                        } else {
-                            break;
-                        }
+    fn quadrant(p: Vector3<f64>) -> usize {
+        (p[0] > 0.) as usize * 2 + (p[2] > 0.) as usize
+    }
+
+    /// Integral of the target function over each quadrant of the plane
+    fn expected() -> [f64; 4] {
+        let mut expected = [0.; 4];
+        let n = 400;
+        let cell = (4. / n as f64).powi(2);
+        for i in 0..n {
+            for j in 0..n {
+                let p = Vector3::new(
+                    4. * (i as f64 + 0.5) / n as f64 - 2.,
+                    0.,
+                    4. * (j as f64 + 0.5) / n as f64 - 2.,
+                );
+                let to_light = Vector3::from(LIGHT) - p;
+                let to_camera = Vector3::new(0., 2., 0.) - p;
+                let cos_light = to_light[1] / to_light.norm();
+                let cos_camera = to_camera[1] / to_camera.norm();
+                let geometry = cos_light / to_light.norm_squared() * cos_camera;
+                let importance = 1. / (cos_camera.powi(3) * to_camera.norm_squared());
+                expected[quadrant(p)] += geometry * importance * 0.5 / PI * cell;
+            }
+        }
+        expected
+    }
+
+    #[test]
+    fn bootstrap_normalizes() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let (scene, settings) = plane_scene();
+        let b: f64 = expected().iter().sum();
+        let seeds = bootstrap(&scene, &settings, rng);
+        assert!((seeds.b - b).abs() < 0.05 * b, "{} vs {}", seeds.b, b);
+    }
+
+    #[test]
+    fn converges_to_target() {
+        let (scene, settings) = plane_scene();
+        let expected = expected();
+        let total: f64 = expected.iter().sum();
 
-                        if !rng.gen_bool(CONTINUE_CHANCE) {
-                            break;
-                        }
-                        prob *= CONTINUE_CHANCE;
+        let rng = &mut StdRng::seed_from_u64(1);
+        let seeds = bootstrap(&scene, &settings, rng);
+        // perturbations only take small steps, so each chain stays near where
+        // it started for a while. Many short chains, which start in proportion
+        // to the target, get around the plane between them.
+        let mut counts = [0; 4];
+        let (chains, steps) = (1000, 1000);
+        for _ in 0..chains {
+            let mut path = seeds.seed(rng).unwrap().clone();
+            let mut value = path.measure(&scene, &settings);
+            for _ in 0..steps {
+                let (next, next_value) = step(path, value, &scene, &settings, rng);
+                path = next;
+                value = next_value;
+                assert_eq!(path.objects.len(), 1);
+                counts[quadrant(path.points[1])] += 1;
+            }
+        }
+        for (count, expected) in counts.iter().zip(&expected) {
+            let fraction = *count as f64 / (chains * steps) as f64;
+            let expected = expected / total;
+            assert!(
+                (fraction - expected).abs() < 0.02,
+                "{} vs {}",
+                fraction,
+                expected
+            );
+        }
+    }
 
-                        // add a new light point
-                        let (p, r) = light_objects
-                            .last()
-                            .unwrap()
-                            .material
-                            .propose(*light_normals.last().unwrap(), rng);
-                        prob *= p;
-                        let ray = Ray::new(*light_points.last().unwrap(), r);
-                        if let Some((t, n, o)) = self.cast(ray) {
-                            light_points.push(ray.of(t));
-                            light_normals.push(n);
-                            light_objects.push(o);
-                        } else {
-                            break;
-                        }
-                    }
+    #[test]
+    fn perturbation_densities() {
+        // summing one over the density of each perturbed point that lands in
+        // a patch of the plane should measure the patch's area
+        let (scene, settings) = plane_scene();
+        let start = Vector3::new(0.3, 0., -0.2);
+        let path = Path {
+            light: &scene.lights[0],
+            light_normal: Vector3::zeros(),
+            objects: vec![&scene.objects()[0]],
+            camera: &scene.camera,
+            points: vec![Vector3::from(LIGHT), start, scene.camera.pos],
+            normals: vec![Vector3::y()],
+        };
+        let rng = &mut StdRng::seed_from_u64(1);
+        let samples = 200_000;
+        // a lens perturbation, then a caustic one from the light, with the
+        // patch given as its offset from the start along both axes
+        for &(from, (min, max)) in &[(2, (0.05, 0.2)), (0, (0.01, 0.06))] {
+            let mut area = 0.;
+            for _ in 0..samples {
+                let (new, forward, _) = path.perturbation(from, 1, &scene, &settings, rng).unwrap();
+                let offset = new.points[1] - start;
+                if (min..max).contains(&offset[0]) && (min..max).contains(&offset[2]) {
+                    area += 1. / forward;
                 }
             }
+            area /= samples as f64;
+            let expected: f64 = (max - min) * (max - min);
+            assert!(
+                (area - expected).abs() < 0.05 * expected,
+                "{} vs {}",
+                area,
+                expected
+            );
         }
-        light_points.extend(camera_points.iter().rev());
-        light_objects.extend(camera_objects.iter().rev());
-        light_normals.extend(camera_normals.iter().rev());
-        (
-            prob,
-            Path {
-                light,
-                objects: light_objects,
-                camera,
-                points: light_points,
-                normals: light_normals,
-            },
-        )
     }
 }
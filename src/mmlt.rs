@@ -0,0 +1,173 @@
+//! Multiplexed MLT (Hachisuka, Kaplanyan and Dachsbacher 2014). Primary
+//! sample space chains, like `pssmlt`, but each chain only explores paths
+//! with a fixed number of surface points, and a primary sample picks which
+//! bidirectional (s, t) connection builds the path. A path's value is
+//! weighted by that technique's MIS weight, so the chain drifts towards
+//! whichever technique is best at sampling it. Each path length gets its own
+//! bootstrap, and the chains are shared out between lengths according to how
+//! much light each one carries.
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{self, Rng};
+
+use crate::bdpt::mis_weight;
+use crate::bootstrap::Bootstrap;
+use crate::camera::ImageBuffer;
+use crate::mlt::{walk, Path};
+use crate::pssmlt::{chain, PrimarySamples, Sample};
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+
+/// Primary sample streams: one for the camera subpath, one for the light
+/// subpath and one for picking how to connect them
+const STREAMS: usize = 3;
+const CAMERA_STREAM: usize = 0;
+const LIGHT_STREAM: usize = 1;
+const CONNECT_STREAM: usize = 2;
+
+pub fn draw<R: Rng + ?Sized>(
+    n: usize,
+    seeds: &[Bootstrap<Vec<f64>>],
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let weights = match WeightedIndex::new(seeds.iter().map(|s| s.b)) {
+        Ok(weights) => weights,
+        Err(_) => return,
+    };
+    // chains go to each length in proportion to its brightness, so all of
+    // them are scaled by the total
+    let length = weights.sample(rng);
+    let seed = match seeds[length].seed(rng) {
+        Some(seed) => seed,
+        None => return,
+    };
+    let b: f64 = seeds.iter().map(|s| s.b).sum();
+    let weight = b / (n as f64 * settings.film_area());
+    let samples = PrimarySamples::new(rng, settings.large_step_probability, STREAMS, seed);
+    chain(n, samples, weight, image, |samples| {
+        evaluate(scene, settings, length + 1, samples)
+    });
+}
+
+/// Bootstrap the chains for each path length, from one surface point up to
+/// `max_depth` of them
+pub fn bootstrap<R: Rng + ?Sized>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Vec<Bootstrap<Vec<f64>>> {
+    (1..=settings.max_depth)
+        .map(|depth| {
+            Bootstrap::new(settings.bootstrap_samples, || {
+                let mut samples =
+                    PrimarySamples::new(&mut *rng, settings.large_step_probability, STREAMS, &[]);
+                let sample = evaluate(scene, settings, depth, &mut samples);
+                Some((sample.luminance, samples.values()))
+            })
+        })
+        .collect()
+}
+
+/// Turn the primary samples into a path with `depth` surface points, using
+/// the connection technique they pick, and see what it contributes
+fn evaluate<R: Rng>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    depth: usize,
+    samples: &mut PrimarySamples<R>,
+) -> Sample {
+    // surface points on the light subpath, out of depth + 1 techniques
+    samples.start_stream(CONNECT_STREAM);
+    let techniques = depth + 1;
+    let split = ((samples.gen::<f64>() * techniques as f64) as usize).min(depth);
+
+    samples.start_stream(CAMERA_STREAM);
+    let camera = &scene.camera;
+    let camera_side = if split == depth {
+        // the light subpath goes straight to the camera
+        vec![]
+    } else {
+        let dir = camera.sample(settings, samples);
+        match walk(scene, camera.pos, dir, depth - split, samples) {
+            Some(vertices) => vertices,
+            None => return Sample::new(None, 0.),
+        }
+    };
+
+    samples.start_stream(LIGHT_STREAM);
+    let light = match scene.sample_light(samples) {
+        Some(light) => light,
+        None => return Sample::new(None, 0.),
+    };
+    let (start, normal, emitter) = light;
+    let light_side = if split == 0 {
+        vec![]
+    } else {
+        match walk(
+            scene,
+            start,
+            emitter.propose(normal, samples).1,
+            split,
+            samples,
+        ) {
+            Some(vertices) => vertices,
+            None => return Sample::new(None, 0.),
+        }
+    };
+
+    let path = Path::connect(light, &light_side, &camera_side, camera);
+    let density = path.split_pdf(0, path.last(), split, settings)
+        * scene.light_pdf(emitter, normal)
+        / techniques as f64;
+    if density == 0. {
+        return Sample::new(None, 0.);
+    }
+    Sample::new(
+        camera.contribution(&path, scene, settings),
+        mis_weight(&path, split, 1., settings) / density,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::pathtracer;
+    use crate::scene::corner;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn lengths_add_up() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // light is lost at every bounce, so paths any longer than this hardly
+        // carry any
+        let scene = corner(Material::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            bootstrap_samples: 40_000,
+            max_depth: 6,
+            ..Default::default()
+        };
+        let total: f64 = bootstrap(&scene, &settings, rng).iter().map(|s| s.b).sum();
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            pathtracer::draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let reference: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let reference = reference.luminance() * settings.film_area()
+            / (settings.width * settings.height) as f64;
+        assert!(
+            (total - reference).abs() < 0.05 * reference,
+            "{} vs {}",
+            total,
+            reference
+        );
+    }
+}
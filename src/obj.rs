@@ -0,0 +1,418 @@
+//! Wavefront OBJ and MTL import.
+//!
+//! Every group (or object) and material pair in the file becomes one mesh
+//! object. Polygons are split into triangle fans, so they should be convex.
+//! MTL materials are mapped onto the closest thing in [`Material`]: anything
+//! with an emission color (`Ke`) glows, anything see-through (`d` or `Tr`, or
+//! an illumination model with refraction) is a dielectric with refractive
+//! index `Ni` tinted by `Tf`, and the rest get a diffuse lobe from `Kd`, a
+//! Phong lobe from `Ks` and `Ns`, or a combination of both. `Ni` alone doesn't
+//! make a material see-through, since exporters write it for every material.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::color::Color;
+use crate::loader::LoadError;
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::scene::{Object, Shape};
+
+/// Everything an MTL file can say about a material that we understand
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtlMaterial {
+    pub diffuse: Color,
+    pub specular: Color,
+    pub exponent: f64,
+    pub emission: Color,
+    pub dissolve: f64,
+    pub ior: f64,
+    pub filter: Color,
+    pub illumination: u32,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            diffuse: Color::new(0.8, 0.8, 0.8),
+            specular: Color::new(0., 0., 0.),
+            exponent: 1.,
+            emission: Color::new(0., 0., 0.),
+            dissolve: 1.,
+            ior: 1.,
+            filter: Color::new(1., 1., 1.),
+            illumination: 2,
+        }
+    }
+}
+
+impl MtlMaterial {
+    pub fn to_material(&self) -> Material {
+        if !is_black(self.emission) {
+            return Material::Emissive(self.emission, 1.);
+        }
+        // illumination models 4, 6, 7 and 9 are the ones with refraction
+        if self.dissolve < 1. || matches!(self.illumination, 4 | 6 | 7 | 9) {
+            return Material::Dielectric {
+                ior: self.ior,
+                tint: self.filter,
+            };
+        }
+        let diffuse = Material::Diffuse(self.diffuse);
+        let specular = Material::Specular(self.specular, self.exponent);
+        match (is_black(self.diffuse), is_black(self.specular)) {
+            (false, true) | (true, true) => diffuse,
+            (true, false) => specular,
+            (false, false) => {
+                // the two lobes add up, so they can't reflect more than all
+                // of the light between them
+                let total = self.diffuse + self.specular;
+                let weight = 1. / total.r.max(total.g).max(total.b).max(1.);
+                Material::Combined(vec![(weight, diffuse), (weight, specular)])
+            }
+        }
+    }
+}
+
+fn is_black(c: Color) -> bool {
+    c.r <= 0. && c.g <= 0. && c.b <= 0.
+}
+
+/// Load every mesh in an OBJ file, along with the materials from any MTL
+/// libraries it references (relative to the OBJ file).
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Vec<Object>, LoadError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_owned(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    parse_obj(&text, path, |name| {
+        let mtl = dir.join(name);
+        let text = fs::read_to_string(&mtl).map_err(|e| LoadError::Io(mtl.clone(), e))?;
+        parse_mtl(&text, &mtl)
+    })
+}
+
+/// Parse the text of an OBJ file. `mtllib` is called to load each material
+/// library the file references, and `path` is only used in error messages.
+pub fn parse_obj<F>(text: &str, path: &Path, mut mtllib: F) -> Result<Vec<Object>, LoadError>
+where
+    F: FnMut(&str) -> Result<HashMap<String, MtlMaterial>, LoadError>,
+{
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut materials = HashMap::new();
+
+    let mut objects = vec![];
+    let mut builder = MeshBuilder::default();
+    let mut material = MtlMaterial::default();
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| LoadError::Syntax(path.to_owned(), number + 1, message);
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<_> = words.collect();
+        match keyword {
+            "v" => positions.push(vector(&args).map_err(error)?),
+            "vn" => normals.push(vector(&args).map_err(error)?),
+            "vt" => {
+                let uv = numbers::<f64>(&args, 2).map_err(error)?;
+                uvs.push(Vector2::new(uv[0], uv[1]))
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(format!(
+                        "faces need at least 3 vertices, found {}",
+                        args.len()
+                    )));
+                }
+                let mut face = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let vertex = face_vertex(arg, positions.len(), uvs.len(), normals.len())
+                        .map_err(error)?;
+                    face.push(builder.vertex(vertex, &positions, &uvs, &normals));
+                }
+                for i in 1..face.len() - 1 {
+                    builder.triangles.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            "g" | "o" => builder.finish(&material, &mut objects).map_err(error)?,
+            "usemtl" => {
+                builder.finish(&material, &mut objects).map_err(error)?;
+                let name = args.join(" ");
+                material = materials
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| error(format!("unknown material {}", name)))?;
+            }
+            "mtllib" => {
+                for name in args {
+                    materials.extend(mtllib(name)?);
+                }
+            }
+            // smoothing groups and the like don't mean anything here
+            _ => {}
+        }
+    }
+    builder
+        .finish(&material, &mut objects)
+        .map_err(|e| LoadError::Syntax(path.to_owned(), text.lines().count(), e))?;
+    Ok(objects)
+}
+
+/// Parse the text of an MTL file into its named materials.
+pub fn parse_mtl(text: &str, path: &Path) -> Result<HashMap<String, MtlMaterial>, LoadError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| LoadError::Syntax(path.to_owned(), number + 1, message);
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<_> = words.collect();
+        if keyword == "newmtl" {
+            materials.extend(current.take());
+            current = Some((args.join(" "), MtlMaterial::default()));
+            continue;
+        }
+        let material = match &mut current {
+            Some((_, material)) => material,
+            None => return Err(error(format!("{} before any newmtl", keyword))),
+        };
+        match keyword {
+            "Kd" => material.diffuse = color(&args).map_err(error)?,
+            "Ks" => material.specular = color(&args).map_err(error)?,
+            "Ke" => material.emission = color(&args).map_err(error)?,
+            "Ns" => material.exponent = numbers(&args, 1).map_err(error)?[0],
+            "Ni" => material.ior = numbers(&args, 1).map_err(error)?[0],
+            "d" => material.dissolve = numbers(&args, 1).map_err(error)?[0],
+            "Tr" => material.dissolve = 1. - numbers::<f64>(&args, 1).map_err(error)?[0],
+            "Tf" => material.filter = color(&args).map_err(error)?,
+            "illum" => material.illumination = numbers(&args, 1).map_err(error)?[0],
+            // ambient colors and texture maps are ignored
+            _ => {}
+        }
+    }
+    materials.extend(current);
+    Ok(materials)
+}
+
+/// Collects the triangles of the current group, merging vertices that share
+/// the same position, uv and normal indices.
+#[derive(Debug, Default)]
+struct MeshBuilder {
+    indices: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    positions: Vec<Vector3<f64>>,
+    uvs: Vec<Option<Vector2<f64>>>,
+    normals: Vec<Option<Vector3<f64>>>,
+    triangles: Vec<[usize; 3]>,
+}
+
+impl MeshBuilder {
+    fn vertex(
+        &mut self,
+        key: (usize, Option<usize>, Option<usize>),
+        positions: &[Vector3<f64>],
+        uvs: &[Vector2<f64>],
+        normals: &[Vector3<f64>],
+    ) -> usize {
+        let MeshBuilder {
+            indices,
+            positions: ps,
+            uvs: ts,
+            normals: ns,
+            ..
+        } = self;
+        *indices.entry(key).or_insert_with(|| {
+            let (p, t, n) = key;
+            ps.push(positions[p]);
+            ts.push(t.map(|t| uvs[t]));
+            ns.push(n.map(|n| normals[n]));
+            ps.len() - 1
+        })
+    }
+    /// Turn the triangles collected so far into an object, and start over.
+    fn finish(&mut self, material: &MtlMaterial, objects: &mut Vec<Object>) -> Result<(), String> {
+        let builder = std::mem::take(self);
+        if builder.triangles.is_empty() {
+            return Ok(());
+        }
+        // a mesh has either a normal (or uv) for every vertex, or none at all
+        let normals = builder.normals.into_iter().collect::<Option<_>>();
+        let uvs = builder.uvs.into_iter().collect::<Option<_>>();
+        let mesh = Mesh::new(
+            builder.positions,
+            normals.unwrap_or_default(),
+            uvs.unwrap_or_default(),
+            builder.triangles,
+        )?;
+        objects.push(Object {
+            shape: Shape::Mesh(Arc::new(mesh)),
+            material: material.to_material(),
+        });
+        Ok(())
+    }
+}
+
+/// Parse one `v/vt/vn` face vertex into zero-based indices. Negative indices
+/// count back from the most recent element.
+fn face_vertex(
+    arg: &str,
+    n_positions: usize,
+    n_uvs: usize,
+    n_normals: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let index = |s: &str, len: usize| -> Result<usize, String> {
+        let i: i64 = s
+            .parse()
+            .map_err(|_| format!("invalid vertex index {}", s))?;
+        let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+        if i == 0 || resolved < 0 || resolved >= len as i64 {
+            return Err(format!("vertex index {} out of range", i));
+        }
+        Ok(resolved as usize)
+    };
+    let optional = |s: Option<&str>, len: usize| match s {
+        None | Some("") => Ok(None),
+        Some(s) => index(s, len).map(Some),
+    };
+    let mut parts = arg.split('/');
+    let position = index(parts.next().unwrap(), n_positions)?;
+    let uv = optional(parts.next(), n_uvs)?;
+    let normal = optional(parts.next(), n_normals)?;
+    Ok((position, uv, normal))
+}
+
+fn numbers<T: FromStr>(args: &[&str], n: usize) -> Result<Vec<T>, String> {
+    if args.len() < n {
+        return Err(format!("expected {} numbers, found {}", n, args.len()));
+    }
+    args[..n]
+        .iter()
+        .map(|s| s.parse().map_err(|_| format!("invalid number {}", s)))
+        .collect()
+}
+
+fn vector(args: &[&str]) -> Result<Vector3<f64>, String> {
+    let v = numbers(args, 3)?;
+    Ok(Vector3::new(v[0], v[1], v[2]))
+}
+
+fn color(args: &[&str]) -> Result<Color, String> {
+    let v = numbers(args, 3)?;
+    Ok(Color::new(v[0], v[1], v[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Ray;
+
+    const MTL: &str = "
+newmtl red
+Kd 1 0 0
+newmtl shiny
+Kd 0.5 0.5 0.5
+Ks 0.5 0.5 0.5
+Ns 50
+";
+
+    fn parse(obj: &str) -> Result<Vec<Object>, LoadError> {
+        parse_obj(obj, Path::new("test.obj"), |_| {
+            parse_mtl(MTL, Path::new("test.mtl"))
+        })
+    }
+
+    #[test]
+    fn groups_and_materials() {
+        let objects = parse(
+            "
+mtllib test.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vn 0 0 1
+g floor
+usemtl red
+f 1//1 2//1 3//1 4//1
+g wall
+usemtl shiny
+f -4 -3 -2
+",
+        )
+        .unwrap();
+        assert_eq!(objects.len(), 2);
+        assert!(matches!(objects[0].material, Material::Diffuse(c) if c == Color::new(1., 0., 0.)));
+        assert!(matches!(&objects[1].material, Material::Combined(m) if m.len() == 2));
+        // the quad was split into two triangles
+        let ray = Ray::new(Vector3::new(0.9, 0.1, 1.), -Vector3::z());
+        assert!(objects[0].shape.cast(ray).is_some());
+        let ray = Ray::new(Vector3::new(0.1, 0.9, 1.), -Vector3::z());
+        assert!(objects[0].shape.cast(ray).is_some());
+    }
+
+    #[test]
+    fn mtl_materials() {
+        let materials = parse_mtl(
+            "
+newmtl lamp
+Ke 4 4 4
+newmtl glass
+Ni 1.5
+d 0.2
+newmtl water
+Ni 1.33
+illum 7
+newmtl plastic
+Kd 0.8 0.8 0.8
+Ks 0.4 0.4 0.4
+Ni 1.45
+",
+            Path::new("test.mtl"),
+        )
+        .unwrap();
+        let lamp = materials["lamp"].to_material();
+        assert!(matches!(lamp, Material::Emissive(c, s) if c == Color::new(4., 4., 4.) && s == 1.));
+        let glass = materials["glass"].to_material();
+        assert!(matches!(glass, Material::Dielectric { ior, .. } if ior == 1.5));
+        let water = materials["water"].to_material();
+        assert!(matches!(water, Material::Dielectric { ior, .. } if ior == 1.33));
+        // the lobes are scaled down to reflect no more than all the light
+        match materials["plastic"].to_material() {
+            Material::Combined(parts) => {
+                assert!(parts.iter().all(|&(w, _)| (w - 1. / 1.2).abs() < 1e-12))
+            }
+            m => panic!("{:?}", m),
+        }
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        let err = parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("test.obj:3"), "{}", err);
+        let err = parse("v 0 0\n").unwrap_err().to_string();
+        assert!(err.contains("test.obj:1"), "{}", err);
+        let err = parse("usemtl blue\n").unwrap_err().to_string();
+        assert!(err.contains("unknown material blue"), "{}", err);
+    }
+
+    #[test]
+    fn example_model() {
+        let objects = load_obj("scenes/models/cube.obj").unwrap();
+        assert_eq!(objects.len(), 2);
+    }
+}
@@ -0,0 +1,408 @@
+//! A plain path tracer, to check the Markov chain integrators against. Paths
+//! are traced out from the camera one pixel at a time, connecting to every
+//! light at each surface they hit, and cut short by Russian roulette. It
+//! measures the same light as `Camera::contribution`, including any light's
+//! attenuation, so a converged render should match the other integrators --
+//! except for caustics from point, spot and directional lights, which it can
+//! never run into after a specular bounce.
+
+use rand::Rng;
+
+use crate::bsdf::Bsdf;
+use crate::camera::ImageBuffer;
+use crate::color::Color;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::vector::Ray;
+use crate::MIN_DIST;
+
+/// Bounces before Russian roulette can end a path
+const MIN_BOUNCES: usize = 2;
+/// Bounces after which a path ends regardless, in case Russian roulette never
+/// does (it keeps every path that loses no light)
+const MAX_BOUNCES: usize = 64;
+
+/// Trace `n` paths through random points in `pixel`, and add their average to
+/// the image
+pub fn draw<R: Rng + ?Sized>(
+    pixel: usize,
+    n: usize,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let mut total = Color::new(0., 0., 0.);
+    for _ in 0..n {
+        let dir = scene.camera.sample_pixel(pixel, settings, rng);
+        total += radiance(scene, Ray::new(scene.camera.pos, dir), rng);
+    }
+    image.buffer.lock().unwrap()[pixel] += total / n as f64;
+}
+
+/// Estimate the light arriving back along `ray`, per unit area of the film
+fn radiance<R: Rng + ?Sized>(scene: &Scene, mut ray: Ray<f64>, rng: &mut R) -> Color {
+    let mut color = Color::new(0., 0., 0.);
+    // what the path so far does to light arriving at its last point
+    let mut throughput = Color::new(1., 1., 1.);
+    // whether lights have been sampled from any point on the path yet, and
+    // whether the ray has to find them itself, after a specular bounce
+    // (lights seen through only specular surfaces are drawn separately)
+    let (mut sampled, mut unsampled) = (false, false);
+    for bounce in 0..MAX_BOUNCES {
+        let hit = scene.cast(ray);
+        if unsampled {
+            color += throughput * scene.emitted(ray, hit);
+        }
+        let (t, normal, object, _) = match hit {
+            Some(hit) => hit,
+            None => break,
+        };
+        let point = ray.of(t);
+        let outgoing = -ray.dir;
+        if outgoing.dot(&normal) <= 0. && !object.material.transmits() {
+            break;
+        }
+        let shading = object.material.at(normal);
+        let specular = object.material.specular().is_some();
+        unsampled = sampled && specular;
+        sampled |= !specular;
+
+        // next event estimation, which can never land on the one direction a
+        // specular surface takes light from
+        for light in scene.lights.iter().filter(|_| !specular) {
+            let (light_point, light_normal) = match light.sample_towards(point, rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let incoming = light_point - point;
+            if scene.occluded(Ray::new(point, incoming), 1. - MIN_DIST) {
+                continue;
+            }
+            let d2 = incoming.magnitude_squared();
+            let cos = incoming.normalize().dot(&normal).abs();
+            let emitted = light.emitted(light_normal, -incoming) / light.point_pdf(light_normal);
+            let spread = if light.is_distant() {
+                1.
+            } else {
+                light.attenuate(d2) / d2
+            };
+            let bsdf = shading.eval(incoming, outgoing);
+            color += throughput * emitted * bsdf * (cos * spread);
+        }
+
+        let (pdf, dir) = shading.sample(outgoing, rng);
+        let cos = dir.dot(&normal).abs();
+        throughput *= shading.eval(dir, outgoing) * (cos / pdf);
+        if bounce >= MIN_BOUNCES {
+            let survive = throughput.luminance().min(1.);
+            if rng.gen::<f64>() >= survive {
+                break;
+            }
+            throughput *= 1. / survive;
+        }
+        ray = Ray::new(point, dir);
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::environment::Environment;
+    use crate::material::Material;
+    use crate::microfacet::Distribution;
+    use crate::mlt;
+    use crate::scene::{corner, corner_lit, Light, Object, Scene, Shape};
+    use nalgebra::Vector3;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let scene = corner(Material::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        // each pixel is the average over its share of the film
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+
+    #[test]
+    fn glass_matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // a light set into a block of glass, above where the camera can see,
+        // shining out through the bottom of it. Only the camera side can find
+        // the light through the glass for the path tracer, and only the light
+        // side for the bootstrap, so they have to agree on refraction.
+        let light = Light::area(
+            Shape::Quad {
+                corner: Vector3::new(-2., 2.6, -2.),
+                edges: [Vector3::new(4., 0., 0.), Vector3::new(0., 0., 4.)],
+            },
+            Color::new(4., 4., 4.),
+        )
+        .unwrap();
+        let glass = Object {
+            shape: Shape::Quad {
+                corner: Vector3::new(-2., 2.2, -2.),
+                edges: [Vector3::new(4., 0., 0.), Vector3::new(0., 0., 4.)],
+            },
+            material: Material::Dielectric {
+                ior: 1.5,
+                tint: Color::new(1., 1., 1.),
+            },
+        };
+        let corner = corner_lit(Material::Diffuse(Color::new(0.5, 0.5, 0.5)), light);
+        let scene = Scene::new(
+            corner.camera.clone(),
+            vec![
+                corner.objects()[0].clone(),
+                corner.objects()[1].clone(),
+                glass,
+            ],
+            corner.lights.clone(),
+        );
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            bootstrap_samples: 1_000_000,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 512, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+
+    #[test]
+    fn area_light_matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // a square light facing the floor, which hides part of it from the
+        // camera above
+        let shape = Shape::Quad {
+            corner: Vector3::new(0.3, 1., 0.05),
+            edges: [Vector3::new(0.4, 0., 0.), Vector3::new(0., 0., 0.4)],
+        };
+        let light = Light::area(shape, Color::new(4., 4., 4.)).unwrap();
+        let scene = corner_lit(Material::Diffuse(Color::new(0.5, 0.5, 0.5)), light);
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+
+    #[test]
+    fn rough_metal_matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // the same light, with a gold wall beside it
+        let shape = Shape::Quad {
+            corner: Vector3::new(0.3, 1., 0.05),
+            edges: [Vector3::new(0.4, 0., 0.), Vector3::new(0., 0., 0.4)],
+        };
+        let light = Light::area(shape, Color::new(4., 4., 4.)).unwrap();
+        let gold = Material::Conductor {
+            distribution: Distribution::Ggx,
+            roughness: 0.3,
+            eta: Color::new(0.2, 0.9, 1.1),
+            k: Color::new(3.9, 2.4, 2.1),
+        };
+        let scene = corner_lit(gold, light);
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+
+    #[test]
+    fn spot_sun_and_glow_match_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let gray = Material::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let mut spot = Light::spot(
+            Vector3::new(0.5, 1., 0.25),
+            Vector3::new(0., -1., 0.2),
+            0.6,
+            0.2,
+            Color::new(2., 2., 2.),
+        );
+        // fading with distance, which only counts on the light's first step
+        spot.attenuation = 0.5;
+        let corner = corner_lit(gray, spot);
+        // the floor and wall are infinite planes, which sunlight has to come
+        // in over as far as the camera can see them
+        let mut objects = corner.objects().to_vec();
+        objects.push(Object {
+            shape: Shape::Sphere {
+                center: Vector3::new(0.4, 0.3, -0.5),
+                radius: 0.15,
+            },
+            material: Material::Emissive(Color::new(1., 1., 1.), 3.),
+        });
+        // travelling away from the wall, which only gets light off the floor
+        let sun = Light::directional(Vector3::new(-0.3, -1., 0.2), Color::new(0.25, 0.25, 0.25));
+        let mut lights = corner.lights;
+        lights.push(sun);
+        let scene = Scene::new(corner.camera, objects, lights);
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            // the small glowing sphere makes for a noisy estimate
+            bootstrap_samples: 400_000,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+
+    #[test]
+    fn environment_lights_floor() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        // a sky that's brighter on one side, so directions aren't all picked
+        // alike, and a dark ground below
+        let (width, height) = (16, 8);
+        let sky = |x: usize, y: usize| match (x, y) {
+            (_, 4..=7) => Color::new(0.1, 0.1, 0.1),
+            (3..=5, _) => Color::new(4., 3., 2.),
+            _ => Color::new(0.2, 0.3, 0.5),
+        };
+        let colors = (0..width * height).map(|i| sky(i % width, i / width));
+        let map = Environment::new(width, height, colors.collect(), 0.5);
+        let floor = Object {
+            shape: Shape::Quad {
+                corner: Vector3::new(-3., 0., -3.),
+                edges: [Vector3::new(0., 0., 6.), Vector3::new(6., 0., 0.)],
+            },
+            material: Material::Diffuse(Color::new(0.5, 0.5, 0.5)),
+        };
+        // the camera only sees the floor, which sees all of the sky
+        let camera = corner(Material::Diffuse(Color::new(0., 0., 0.))).camera;
+        let scene = Scene::new(camera, vec![floor], vec![Light::environment(map, 1.)]);
+        // the diffuse floor reflects the cosine weighted sky, row by row
+        let mut irradiance = Color::new(0., 0., 0.);
+        for y in 0..height / 2 {
+            let sin2 = |y: usize| (y as f64 / height as f64 * PI).sin().powi(2);
+            for x in 0..width {
+                irradiance += sky(x, y) * (PI / width as f64 * (sin2(y + 1) - sin2(y)));
+            }
+        }
+        let expected = irradiance.luminance() * 0.5 / PI;
+
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            ..Default::default()
+        };
+        let pixels = (settings.width * settings.height) as f64;
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 16, &scene, &image, &settings, rng);
+        }
+        let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+        let average = total.luminance() / pixels;
+        assert!(
+            (average - expected).abs() < 0.03 * expected,
+            "{} vs {}",
+            average,
+            expected
+        );
+        let b = mlt::bootstrap(&scene, &settings, rng).b / settings.film_area();
+        assert!(
+            (b - expected).abs() < 0.05 * expected,
+            "{} vs {}",
+            b,
+            expected
+        );
+    }
+
+    #[test]
+    fn environment_over_a_plane_matches_bootstrap() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let (width, height) = (8, 4);
+        let sky = |x: usize, y: usize| match (x, y) {
+            (_, 2..=3) => Color::new(0.1, 0.1, 0.1),
+            (1..=2, _) => Color::new(4., 3., 2.),
+            _ => Color::new(0.2, 0.3, 0.5),
+        };
+        let colors = (0..width * height).map(|i| sky(i % width, i / width));
+        let map = Environment::new(width, height, colors.collect(), 0.);
+        let gray = Material::Diffuse(Color::new(0.5, 0.5, 0.5));
+        // the ball is the only thing with bounds, but the disk still has to
+        // cover as much of the infinite floor as the camera sees
+        let corner = corner(gray.clone());
+        let mut objects = corner.objects()[..1].to_vec();
+        objects.push(Object {
+            shape: Shape::Sphere {
+                center: Vector3::new(0.3, 0.3, 0.),
+                radius: 0.3,
+            },
+            material: gray,
+        });
+        let scene = Scene::new(
+            corner.camera.clone(),
+            objects,
+            vec![Light::environment(map, 1.)],
+        );
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            bootstrap_samples: 200_000,
+            ..Default::default()
+        };
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let buffer = image.buffer.lock().unwrap();
+        assert!(buffer.iter().all(|c| c.luminance() > 0.));
+        let total: Color = buffer.iter().copied().sum();
+        let total =
+            total.luminance() * settings.film_area() / (settings.width * settings.height) as f64;
+        let b = mlt::bootstrap(&scene, &settings, rng).b;
+        assert!((total - b).abs() < 0.05 * b, "{} vs {}", total, b);
+    }
+}
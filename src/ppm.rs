@@ -0,0 +1,294 @@
+//! Progressive photon mapping, in the probabilistic form of Knaus and Zwicker
+//! (2011). Each pass traces photons out from the lights and stores them where
+//! they land on diffuse surfaces, then traces a path through every pixel to
+//! the first diffuse surface it sees and counts the photons around it. Passes
+//! are independent estimates, each gathering over a smaller radius than the
+//! last, so their average converges even though every pass is biased. Light
+//! that comes straight from a light is found with shadow rays instead, so the
+//! photons only need to carry indirect light and caustics.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use nalgebra::Vector3;
+use rand::Rng;
+
+use crate::bsdf::Bsdf;
+use crate::camera::ImageBuffer;
+use crate::color::Color;
+use crate::scene::{Object, Scene};
+use crate::settings::RenderSettings;
+use crate::vector::Ray;
+use crate::MIN_DIST;
+
+/// How fast the gather radius shrinks: the area it covers in pass i goes as
+/// i^(ALPHA - 1)
+const ALPHA: f64 = 2. / 3.;
+/// Bounces before Russian roulette can end a photon or camera path
+const MIN_BOUNCES: usize = 2;
+/// Bounces after which a photon or camera path ends regardless, in case
+/// Russian roulette never does (it keeps every path that loses no light)
+const MAX_BOUNCES: usize = 64;
+
+/// Run photon mapping pass number `pass` (counting from 0), adding its share
+/// of the average over every pass to the image
+pub fn draw<R: Rng + ?Sized>(
+    pass: usize,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let photons = PhotonMap::new(trace_photons(scene, settings, rng), radius(pass, settings));
+    let pixels: Vec<Color> = (0..settings.width * settings.height)
+        .map(|pixel| {
+            let dir = scene.camera.sample_pixel(pixel, settings, rng);
+            let ray = Ray::new(scene.camera.pos, dir);
+            radiance(scene, &photons, ray, rng)
+        })
+        .collect();
+    let passes = settings.samples_per_pixel as f64;
+    let mut buffer = image.buffer.lock().unwrap();
+    for (pixel, color) in buffer.iter_mut().zip(pixels) {
+        *pixel += color / passes;
+    }
+}
+
+/// The gather radius for pass number `pass`
+pub fn radius(pass: usize, settings: &RenderSettings) -> f64 {
+    settings.photon_radius * ((pass + 1) as f64).powf((ALPHA - 1.) / 2.)
+}
+
+/// A photon that has landed on a surface, after bouncing at least once
+#[derive(Debug, Clone, Copy)]
+struct Photon {
+    /// Towards where the photon came from
+    incoming: Vector3<f64>,
+    power: Color,
+}
+
+/// Points sorted into a grid of cubes as wide as the gather radius, so that
+/// finding the ones near a point only means looking in the cubes around it
+pub struct PhotonMap<T> {
+    pub radius: f64,
+    cells: HashMap<[i64; 3], Vec<(Vector3<f64>, T)>>,
+}
+
+impl<T> PhotonMap<T> {
+    pub fn new<I: IntoIterator<Item = (Vector3<f64>, T)>>(photons: I, radius: f64) -> Self {
+        let mut cells: HashMap<_, Vec<_>> = HashMap::new();
+        for (point, photon) in photons {
+            cells
+                .entry(cell(point, radius))
+                .or_default()
+                .push((point, photon));
+        }
+        PhotonMap { radius, cells }
+    }
+    /// Everything stored within the gather radius of `point`
+    pub fn near(&self, point: Vector3<f64>) -> impl Iterator<Item = &T> {
+        let [x, y, z] = cell(point, self.radius);
+        let radius = self.radius;
+        (-1..=1)
+            .flat_map(move |dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [dx, dy, dz])))
+            .filter_map(move |[dx, dy, dz]| self.cells.get(&[x + dx, y + dy, z + dz]))
+            .flatten()
+            .filter(move |(p, _)| (p - point).magnitude_squared() <= radius * radius)
+            .map(|(_, photon)| photon)
+    }
+}
+
+impl PhotonMap<Photon> {
+    /// Estimate the light reflected from `point` towards `outgoing`, from the
+    /// photons within the gather radius of it
+    fn gather(
+        &self,
+        point: Vector3<f64>,
+        normal: Vector3<f64>,
+        outgoing: Vector3<f64>,
+        object: &Object,
+    ) -> Color {
+        let mut total = Color::new(0., 0., 0.);
+        let shading = object.material.at(normal);
+        // photons from the other side of the surface don't light this side
+        for photon in self.near(point).filter(|p| p.incoming.dot(&normal) > 0.) {
+            total += photon.power * shading.eval(photon.incoming, outgoing);
+        }
+        total / (PI * self.radius.powi(2))
+    }
+}
+
+fn cell(point: Vector3<f64>, size: f64) -> [i64; 3] {
+    let cell = point / size;
+    [
+        cell[0].floor() as i64,
+        cell[1].floor() as i64,
+        cell[2].floor() as i64,
+    ]
+}
+
+/// Whether paths go on through a surface rather than gathering photons there
+pub fn glossy(object: &Object) -> bool {
+    object.material.glossy()
+}
+
+/// Trace `settings.photons` photons out from the lights, keeping the ones that
+/// land on diffuse surfaces after bouncing at least once
+fn trace_photons<R: Rng + ?Sized>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Vec<(Vector3<f64>, Photon)> {
+    let mut photons = vec![];
+    for _ in 0..settings.photons {
+        let (start, normal, light) = match scene.sample_light(rng) {
+            Some(light) => light,
+            None => break,
+        };
+        let (pdf, dir) = light.propose(normal, rng);
+        let density = scene.light_pdf(light, normal) * pdf * settings.photons as f64;
+        let mut power = light.emitted(normal, dir) / density;
+        let mut ray = Ray::new(start, dir);
+        for bounce in 0..MAX_BOUNCES {
+            let (t, normal, object, _) = match scene.cast(ray) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let point = ray.of(t);
+            let incoming = -ray.dir;
+            if incoming.dot(&normal) <= 0. && !object.material.transmits() {
+                break;
+            }
+            if bounce == 0 {
+                power *= light.attenuate((point - ray.start).magnitude_squared());
+            }
+            if bounce > 0 && !glossy(object) {
+                photons.push((point, Photon { incoming, power }));
+            }
+
+            let shading = object.material.at(normal);
+            let (pdf, dir) = shading.sample(incoming, rng);
+            let cos = dir.dot(&normal).abs();
+            power *= shading.eval(incoming, dir) * (cos / pdf);
+            if bounce >= MIN_BOUNCES {
+                let survive = power.luminance().min(1.);
+                if rng.gen::<f64>() >= survive {
+                    break;
+                }
+                power *= 1. / survive;
+            }
+            ray = Ray::new(point, dir);
+        }
+    }
+    photons
+}
+
+/// Estimate the light arriving back along `ray`, per unit area of the film.
+/// Like the path tracer, but stopping to gather photons at the first diffuse
+/// surface.
+fn radiance<R: Rng + ?Sized>(
+    scene: &Scene,
+    photons: &PhotonMap<Photon>,
+    mut ray: Ray<f64>,
+    rng: &mut R,
+) -> Color {
+    let mut color = Color::new(0., 0., 0.);
+    let mut throughput = Color::new(1., 1., 1.);
+    for bounce in 0..MAX_BOUNCES {
+        let (t, normal, object, _) = match scene.cast(ray) {
+            Some(hit) => hit,
+            None => break,
+        };
+        let point = ray.of(t);
+        let outgoing = -ray.dir;
+        if outgoing.dot(&normal) <= 0. && !object.material.transmits() {
+            break;
+        }
+        let shading = object.material.at(normal);
+
+        let specular = object.material.specular().is_some();
+        for light in scene.lights.iter().filter(|_| !specular) {
+            let (light_point, light_normal) = match light.sample_towards(point, rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let incoming = light_point - point;
+            if scene.occluded(Ray::new(point, incoming), 1. - MIN_DIST) {
+                continue;
+            }
+            let d2 = incoming.magnitude_squared();
+            let cos = incoming.normalize().dot(&normal).abs();
+            let emitted = light.emitted(light_normal, -incoming) / light.point_pdf(light_normal);
+            let spread = if light.is_distant() {
+                1.
+            } else {
+                light.attenuate(d2) / d2
+            };
+            let bsdf = shading.eval(incoming, outgoing);
+            color += throughput * emitted * bsdf * (cos * spread);
+        }
+        if !glossy(object) {
+            color += throughput * photons.gather(point, normal, outgoing, object);
+            break;
+        }
+
+        let (pdf, dir) = shading.sample(outgoing, rng);
+        let cos = dir.dot(&normal).abs();
+        throughput *= shading.eval(dir, outgoing) * (cos / pdf);
+        if bounce >= MIN_BOUNCES {
+            let survive = throughput.luminance().min(1.);
+            if rng.gen::<f64>() >= survive {
+                break;
+            }
+            throughput *= 1. / survive;
+        }
+        ray = Ray::new(point, dir);
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::pathtracer;
+    use crate::scene::corner;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn matches_path_tracer() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let scene = corner(Material::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            samples_per_pixel: 16,
+            photons: 20_000,
+            photon_radius: 0.05,
+            ..Default::default()
+        };
+        let total = |image: &ImageBuffer| {
+            let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+            total.luminance()
+        };
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pass in 0..settings.samples_per_pixel {
+            draw(pass, &scene, &image, &settings, rng);
+        }
+        let ppm = total(&image);
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            pathtracer::draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let reference = total(&image);
+        assert!(
+            (ppm - reference).abs() < 0.05 * reference,
+            "{} vs {}",
+            ppm,
+            reference
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Tracks the render jobs queued on the thread pool, so that the main thread
+/// can tell when they have all finished and can stop them early.
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    finished: Mutex<usize>,
+    all_finished: Condvar,
+    samples: AtomicUsize,
+    sample_limit: Option<usize>,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    pub fn new(total: usize, sample_limit: Option<usize>) -> Self {
+        Progress {
+            total,
+            finished: Mutex::new(0),
+            all_finished: Condvar::new(),
+            samples: AtomicUsize::new(0),
+            sample_limit,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+    /// Run one job unless rendering has been cancelled, then mark it as
+    /// finished. The job returns the number of samples it recorded, which
+    /// counts towards the sample limit.
+    pub fn run<F: FnOnce() -> usize>(&self, job: F) {
+        if !self.is_cancelled() {
+            let n = job();
+            let samples = self.samples.fetch_add(n, Ordering::Relaxed) + n;
+            if matches!(self.sample_limit, Some(limit) if samples >= limit) {
+                self.cancel();
+            }
+        }
+        let mut finished = self.finished.lock().unwrap();
+        *finished += 1;
+        if *finished == self.total {
+            self.all_finished.notify_all();
+        }
+    }
+    /// Skip every job that hasn't started yet.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+    pub fn samples(&self) -> usize {
+        self.samples.load(Ordering::Relaxed)
+    }
+    /// Fraction of jobs that have finished (or been skipped)
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.
+        } else {
+            *self.finished.lock().unwrap() as f64 / self.total as f64
+        }
+    }
+    /// Block until every job has finished, or until the timeout runs out.
+    /// Returns whether all the jobs finished.
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let finished = self.finished.lock().unwrap();
+        let finished = match timeout {
+            Some(timeout) => {
+                self.all_finished
+                    .wait_timeout_while(finished, timeout, |n| *n < self.total)
+                    .unwrap()
+                    .0
+            }
+            None => self
+                .all_finished
+                .wait_while(finished, |n| *n < self.total)
+                .unwrap(),
+        };
+        *finished == self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+
+    #[test]
+    fn waits_for_jobs() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let progress = Arc::new(Progress::new(10, None));
+        for _ in 0..10 {
+            let progress = progress.clone();
+            pool.spawn(move || progress.run(|| 3));
+        }
+        assert!(progress.wait(None));
+        assert_eq!(progress.samples(), 30);
+        assert_eq!(progress.fraction(), 1.);
+    }
+
+    #[test]
+    fn sample_limit_cancels() {
+        let progress = Progress::new(10, Some(5));
+        for _ in 0..10 {
+            progress.run(|| 2);
+        }
+        assert!(progress.wait(Some(Duration::from_secs(0))));
+        assert!(progress.is_cancelled());
+        assert_eq!(progress.samples(), 6);
+    }
+}
@@ -0,0 +1,333 @@
+//! Primary sample space MLT (Kelemen et al. 2002). Rather than mutating a
+//! path directly, the chain mutates the vector of random numbers that
+//! `Scene::propose` turns into a path. Every sampling routine then takes part
+//! in the exploration without needing its own mutation or transition density,
+//! since both kinds of step are symmetric in primary sample space. The value
+//! of a point is the light its path carries divided by the path's density, so
+//! the chain needs no knowledge of how `Scene::propose` works.
+
+use rand::{self, Error, Rng, RngCore};
+use rand_distr::StandardNormal;
+
+use crate::bootstrap::Bootstrap;
+use crate::camera::ImageBuffer;
+use crate::color::Color;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+
+/// Standard deviation of a small step in each primary sample
+const SMALL_STEP: f64 = 0.01;
+
+pub fn draw<R: Rng + ?Sized>(
+    n: usize,
+    seeds: &Bootstrap<Vec<f64>>,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let seed = match seeds.seed(rng) {
+        Some(seed) => seed,
+        None => return,
+    };
+    let weight = seeds.b / (n as f64 * settings.film_area());
+    let samples = PrimarySamples::new(rng, settings.large_step_probability, 1, seed);
+    chain(n, samples, weight, image, |samples| {
+        evaluate(scene, settings, samples)
+    });
+}
+
+/// Run a chain for `n` steps from `samples`, recording each step with the
+/// luminance `weight`. `evaluate` turns the samples into what they contribute
+/// to the image.
+pub fn chain<R, F>(
+    n: usize,
+    mut samples: PrimarySamples<R>,
+    weight: f64,
+    image: &ImageBuffer,
+    mut evaluate: F,
+) where
+    R: Rng,
+    F: FnMut(&mut PrimarySamples<R>) -> Sample,
+{
+    let mut current = evaluate(&mut samples);
+    for _ in 0..n {
+        samples.start_iteration();
+        let proposed = evaluate(&mut samples);
+        let accept = if current.luminance == 0. {
+            1.
+        } else {
+            (proposed.luminance / current.luminance).min(1.)
+        };
+        // record both paths, weighted by their chance of being the next state
+        current.record(image, (1. - accept) * weight);
+        proposed.record(image, accept * weight);
+        if samples.rng.gen::<f64>() < accept {
+            samples.accept();
+            current = proposed;
+        } else {
+            samples.reject();
+        }
+    }
+}
+
+/// Sample independent points in primary sample space, to estimate the
+/// integral of their luminance and to seed the chains with
+pub fn bootstrap<R: Rng + ?Sized>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Bootstrap<Vec<f64>> {
+    Bootstrap::new(settings.bootstrap_samples, || {
+        let mut samples = PrimarySamples::new(&mut *rng, settings.large_step_probability, 1, &[]);
+        let sample = evaluate(scene, settings, &mut samples);
+        Some((sample.luminance, samples.values()))
+    })
+}
+
+/// What a point in primary sample space contributes to the image
+pub struct Sample {
+    pixel: Option<usize>,
+    color: Color,
+    pub luminance: f64,
+}
+
+impl Sample {
+    /// The light a path carries to the camera, as `Camera::contribution` gives
+    /// it, scaled by `scale`
+    pub fn new(contribution: Option<(usize, Color)>, scale: f64) -> Self {
+        match contribution {
+            Some((pixel, color)) => {
+                let color = color * scale;
+                Sample {
+                    pixel: Some(pixel),
+                    color,
+                    luminance: color.luminance(),
+                }
+            }
+            None => Sample {
+                pixel: None,
+                color: Color::new(0., 0., 0.),
+                luminance: 0.,
+            },
+        }
+    }
+    /// Add the sample to the image, scaled so that its luminance is `weight`
+    fn record(&self, image: &ImageBuffer, weight: f64) {
+        if let Some(pixel) = self.pixel {
+            if self.luminance > 0. {
+                image.buffer.lock().unwrap()[pixel] += self.color * (weight / self.luminance);
+            }
+        }
+    }
+}
+
+/// Turn the primary samples into a path and see what it contributes, divided
+/// by the density of sampling it
+fn evaluate<R: Rng + ?Sized>(scene: &Scene, settings: &RenderSettings, samples: &mut R) -> Sample {
+    match scene.propose(settings, samples) {
+        Some((density, path)) => Sample::new(
+            scene.camera.contribution(&path, scene, settings),
+            1. / density,
+        ),
+        None => Sample::new(None, 0.),
+    }
+}
+
+/// One coordinate of the primary sample vector
+#[derive(Debug, Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    /// Iteration the value was last brought up to date
+    modified: usize,
+    backup: f64,
+    modified_backup: usize,
+}
+
+/// A source of random numbers that replays the current point in primary
+/// sample space, mutated by a small or large step each iteration. Samples are
+/// only brought up to date when they are used, so paths can be as long as
+/// they like. (This follows the lazy scheme used by pbrt.)
+///
+/// The samples can be split into interleaved streams, so that each part of
+/// generating a path keeps reading the same samples even when another part
+/// changes how many it uses.
+pub struct PrimarySamples<R> {
+    rng: R,
+    samples: Vec<PrimarySample>,
+    large_step_probability: f64,
+    large_step: bool,
+    iteration: usize,
+    last_large_step: usize,
+    streams: usize,
+    stream: usize,
+    /// Samples used from the current stream so far this iteration
+    index: usize,
+}
+
+impl<R: Rng> PrimarySamples<R> {
+    /// A chain whose first samples are `start`, with the rest random
+    pub fn new(rng: R, large_step_probability: f64, streams: usize, start: &[f64]) -> Self {
+        PrimarySamples {
+            rng,
+            samples: start
+                .iter()
+                .map(|&value| PrimarySample {
+                    value,
+                    modified: 0,
+                    backup: value,
+                    modified_backup: 0,
+                })
+                .collect(),
+            large_step_probability,
+            large_step: false,
+            iteration: 0,
+            last_large_step: 0,
+            streams,
+            stream: 0,
+            index: 0,
+        }
+    }
+    /// Every sample used so far, for starting another chain from
+    pub fn values(&self) -> Vec<f64> {
+        self.samples.iter().map(|sample| sample.value).collect()
+    }
+    /// Begin proposing a mutation of the current samples
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen_bool(self.large_step_probability);
+        self.start_stream(0);
+    }
+    /// Read the following samples from another stream, from its beginning
+    pub fn start_stream(&mut self, stream: usize) {
+        self.stream = stream;
+        self.index = 0;
+    }
+    /// Keep the mutated samples
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+    /// Go back to the samples from before the mutation
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.modified == self.iteration {
+                sample.value = sample.backup;
+                sample.modified = sample.modified_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+    /// The next sample of the current iteration, in 0..1
+    pub fn next_sample(&mut self) -> f64 {
+        let index = self.stream + self.streams * self.index;
+        self.index += 1;
+        while self.samples.len() <= index {
+            // samples that haven't been used yet are as good as any large step
+            let value = self.rng.gen();
+            self.samples.push(PrimarySample {
+                value,
+                modified: self.last_large_step,
+                backup: value,
+                modified_backup: self.last_large_step,
+            });
+        }
+        let rng = &mut self.rng;
+        let sample = &mut self.samples[index];
+        // catch up with any large step accepted since this was last used
+        if sample.modified < self.last_large_step {
+            sample.value = rng.gen();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.modified_backup = sample.modified;
+        if self.large_step {
+            sample.value = rng.gen();
+        } else {
+            // all the small steps it has missed add up to one wider one
+            let steps = (self.iteration - sample.modified) as f64;
+            let offset: f64 = rng.sample(StandardNormal);
+            sample.value += offset * SMALL_STEP * steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+/// Lets the samples stand in for any other random number generator. Each call
+/// uses up exactly one sample, and larger samples give larger numbers, so
+/// small steps in primary sample space stay small once they are turned into
+/// floats or coin flips.
+impl<R: Rng> RngCore for PrimarySamples<R> {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_sample() * 4_294_967_296.) as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        (self.next_sample() * 18_446_744_073_709_551_616.) as u64
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn rejected_steps_are_undone() {
+        let mut samples = PrimarySamples::new(StdRng::seed_from_u64(1), 0., 1, &[]);
+        samples.start_iteration();
+        let accepted: Vec<f64> = (0..4).map(|_| samples.next_sample()).collect();
+        samples.accept();
+        for _ in 0..10 {
+            samples.start_iteration();
+            for _ in 0..6 {
+                assert!((0. ..1.).contains(&samples.next_sample()));
+            }
+            samples.reject();
+        }
+        // so the next proposal is only one small step away
+        samples.start_iteration();
+        for a in accepted {
+            let b = samples.next_sample();
+            let distance = (a - b).abs().min(1. - (a - b).abs());
+            assert!(distance < 6. * SMALL_STEP, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn floats_follow_samples() {
+        let mut samples = PrimarySamples::new(StdRng::seed_from_u64(1), 0., 1, &[0.5, 0.75]);
+        samples.start_iteration();
+        let x: f64 = samples.gen();
+        let y: f64 = samples.gen_range(2. ..4.);
+        assert!((x - 0.5).abs() < 0.1);
+        assert!((y - 3.5).abs() < 0.2);
+    }
+
+    #[test]
+    fn streams_interleave() {
+        let mut samples =
+            PrimarySamples::new(StdRng::seed_from_u64(1), 0., 2, &[0.1, 0.6, 0.2, 0.7]);
+        samples.start_iteration();
+        // using fewer samples from the first stream doesn't shift the second
+        samples.next_sample();
+        samples.start_stream(1);
+        assert!((samples.next_sample() - 0.6).abs() < 0.1);
+        assert!((samples.next_sample() - 0.7).abs() < 0.1);
+        samples.start_stream(0);
+        assert!((samples.next_sample() - 0.1).abs() < 0.1);
+    }
+}
@@ -1,46 +1,193 @@
+use std::convert::TryFrom;
 use std::f64::consts::{PI, TAU};
-use std::f64::INFINITY;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use nalgebra::Vector3;
 use rand::Rng;
+use serde::Deserialize;
 
+use crate::bvh::{Aabb, Bvh};
 use crate::camera::Camera;
 use crate::color::Color;
+use crate::environment::Environment;
 use crate::material::Material;
-use crate::vector::Ray;
+use crate::mesh::{face_normal, intersect_triangle, Mesh, MeshHit};
+use crate::vector::{around, Ray};
 use crate::MIN_DIST;
 
+/// How far out distant lights shine over an infinite plane, as a multiple of
+/// the size of the rest of the scene (or of the camera's height above the
+/// plane, if that's more)
+const PLANE_REACH: f64 = 2.;
+
 #[derive(Debug, Clone)]
 pub struct Scene {
     pub camera: Camera,
-    pub objects: Vec<Object>,
+    /// Kept private, since the BVH is built over them
+    objects: Vec<Object>,
     pub lights: Vec<Light>,
+    bvh: Bvh,
+    /// For each object, the light it's the surface of, if any
+    object_lights: Vec<Option<usize>>,
+    /// The total power of the lights up to and including each one, to pick
+    /// them in proportion to their power
+    light_power: Vec<f64>,
 }
 
 impl Scene {
-    pub fn cast(&self, ray: Ray<f64>) -> Option<(f64, Vector3<f64>, &Object)> {
-        let mut intersection = None;
-        let mut min_dist = INFINITY;
-        for obj in &self.objects {
-            if let Some((t, norm)) = obj.shape.cast(ray) {
-                if t < min_dist {
-                    min_dist = t;
-                    intersection = Some((t, norm, obj))
+    pub fn new(camera: Camera, mut objects: Vec<Object>, mut lights: Vec<Light>) -> Self {
+        let mut object_lights = vec![None; objects.len()];
+        // area lights block light like anything else, but don't reflect any
+        for (i, light) in lights.iter().enumerate() {
+            if let Emitter::Area(surface) = &light.emitter {
+                objects.push(Object {
+                    shape: surface.shape.clone(),
+                    material: Material::Emissive(light.color, 1.),
+                });
+                object_lights.push(Some(i));
+            }
+        }
+        // emissive objects are area lights that are already in the scene
+        // (scene files can't have ones without a finite area)
+        for (object, index) in objects.iter().zip(&mut object_lights) {
+            if let (&Material::Emissive(color, strength), None) = (&object.material, *index) {
+                if let Ok(light) = Light::area(object.shape.clone(), color * strength) {
+                    *index = Some(lights.len());
+                    lights.push(light);
                 }
             }
         }
-        intersection
+        // distant lights shine over everything in the scene that has bounds,
+        // and over the part of each infinite plane that's near the camera
+        let bounds = objects
+            .iter()
+            .flat_map(|o| (0..o.shape.parts()).filter_map(move |part| o.shape.bounds(part)))
+            .fold(Aabb::around(&[camera.pos]), |b, part| b.union(&part));
+        let size = (bounds.max - bounds.min).norm();
+        let bounds = objects
+            .iter()
+            .filter_map(|o| o.shape.near(camera.pos, size))
+            .fold(bounds, |b, part| b.union(&part));
+        for light in &mut lights {
+            if let Emitter::Directional { center, radius, .. }
+            | Emitter::Environment { center, radius, .. } = &mut light.emitter
+            {
+                *center = bounds.centroid();
+                *radius = ((bounds.max - bounds.min).norm() / 2.).max(MIN_DIST);
+            }
+        }
+        let light_power = lights
+            .iter()
+            .scan(0., |total, light| {
+                *total += light.power();
+                Some(*total)
+            })
+            .collect();
+        Scene {
+            bvh: Bvh::new(&objects),
+            camera,
+            objects,
+            lights,
+            object_lights,
+            light_power,
+        }
+    }
+    /// Everything in the scene, including the surfaces of area lights
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+    pub fn cast(&self, ray: Ray<f64>) -> Option<Hit<'_>> {
+        let (t, normal, i) = self.bvh.cast(ray, &self.objects)?;
+        Some((t, normal, &self.objects[i], i))
+    }
+    /// The light given off back along `ray` by the first thing it hits, or by
+    /// the environment if it hits nothing, given that hit. Sampling a light
+    /// can't find one seen by way of a specular surface, so paths through
+    /// them have to run into it instead.
+    pub fn emitted(&self, ray: Ray<f64>, hit: Option<Hit>) -> Color {
+        let (t, normal, _, index) = match hit {
+            Some(hit) => hit,
+            None => {
+                return self
+                    .lights
+                    .iter()
+                    .filter(|light| matches!(light.emitter, Emitter::Environment { .. }))
+                    .map(|light| light.emitted(-ray.dir, -ray.dir))
+                    .sum()
+            }
+        };
+        match self.object_lights[index] {
+            // an area light's radiance, from its front
+            Some(i) if normal.dot(&ray.dir) < 0. => {
+                let light = &self.lights[i];
+                light.color * light.attenuate((ray.dir * t).magnitude_squared())
+            }
+            _ => Color::new(0., 0., 0.),
+        }
+    }
+    /// Whether anything lies along the ray before `t_max`. This stops at the
+    /// first hit found, so it's cheaper than `cast` for shadow tests.
+    pub fn occluded(&self, ray: Ray<f64>, t_max: f64) -> bool {
+        self.bvh.occluded(ray, t_max, &self.objects)
+    }
+    /// Pick a light in proportion to its power, and a point on it for light
+    /// to leave from
+    pub fn sample_light<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<LightVertex<'_>> {
+        let total = *self.light_power.last()?;
+        if total <= 0. {
+            return None;
+        }
+        // one random number for the choice, so that primary sample space
+        // mutations of it stay small
+        let target = rng.gen::<f64>() * total;
+        let i = self.light_power.partition_point(|&power| power <= target);
+        let light = &self.lights[i.min(self.lights.len() - 1)];
+        let (point, normal) = light.sample_point(rng);
+        Some((point, normal, light))
+    }
+    /// Density per unit area of `sample_light` picking any given point on
+    /// `light`, with surface normal `normal` there
+    pub fn light_pdf(&self, light: &Light, normal: Vector3<f64>) -> f64 {
+        match self.light_power.last() {
+            Some(&total) if total > 0. => light.power() / total * light.point_pdf(normal),
+            _ => 0.,
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Where a ray first hits the scene: how far along it, the surface normal
+/// there, and the object hit along with its index in `Scene::objects`
+pub type Hit<'a> = (f64, Vector3<f64>, &'a Object, usize);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ObjectDesc")]
 pub struct Object {
     pub material: Material,
     pub shape: Shape,
 }
 
-#[derive(Debug, Clone)]
+/// How an object is written in a scene file, before checking that it can
+/// glow if it's emissive
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    material: Material,
+    shape: Shape,
+}
+
+impl TryFrom<ObjectDesc> for Object {
+    type Error = String;
+    fn try_from(ObjectDesc { material, shape }: ObjectDesc) -> Result<Self, String> {
+        if let Material::Emissive(..) = material {
+            Surface::new(shape.clone())?;
+        }
+        Ok(Object { material, shape })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub enum Shape {
     Sphere {
         center: Vector3<f64>,
@@ -50,10 +197,69 @@ pub enum Shape {
         center: Vector3<f64>,
         normal: Vector3<f64>,
     },
+    Triangle {
+        vertices: [Vector3<f64>; 3],
+    },
+    /// A parallelogram with one corner at `corner`, and sides along `edges`
+    Quad {
+        corner: Vector3<f64>,
+        edges: [Vector3<f64>; 2],
+    },
+    Mesh(Arc<Mesh>),
 }
 
 impl Shape {
-    pub fn cast(&self, ray: Ray<f64>) -> Option<(f64, Vector3<f64>)> {
+    /// How many separately bounded pieces the shape is made of
+    pub fn parts(&self) -> usize {
+        match self {
+            Shape::Mesh(mesh) => mesh.triangle_count(),
+            _ => 1,
+        }
+    }
+    /// Bounding box of one part, or `None` if it's infinite
+    pub fn bounds(&self, part: usize) -> Option<Aabb> {
+        match self {
+            Shape::Sphere { center, radius } => Some(Aabb {
+                min: center - Vector3::repeat(*radius),
+                max: center + Vector3::repeat(*radius),
+            }),
+            Shape::Plane { .. } => None,
+            Shape::Triangle { vertices } => Some(Aabb::around(vertices)),
+            Shape::Quad { .. } => Some(Aabb::around(&self.triangles().concat())),
+            Shape::Mesh(mesh) => Some(Aabb::around(&mesh.vertices(part))),
+        }
+    }
+    /// For an infinite plane, the bounds of the square of it around the point
+    /// nearest `pos`, reaching `PLANE_REACH` times `size` or the distance to
+    /// `pos` out from there, whichever is more. `None` for any other shape.
+    pub fn near(&self, pos: Vector3<f64>, size: f64) -> Option<Aabb> {
+        let (center, normal) = match self {
+            Shape::Plane { center, normal } => (center, normal.normalize()),
+            _ => return None,
+        };
+        let height = (pos - center).dot(&normal);
+        let foot = pos - normal * height;
+        let reach = PLANE_REACH * size.max(height.abs());
+        let corners = (0..4)
+            .map(|i| foot + around(normal, 0., i as f64 * PI / 2.) * reach)
+            .collect::<Vec<_>>();
+        Some(Aabb::around(&corners))
+    }
+    /// Like `cast`, but only against one part of the shape
+    pub fn cast_part(&self, part: usize, ray: Ray<f64>) -> Option<(f64, Vector3<f64>, bool)> {
+        match self {
+            Shape::Mesh(mesh) => {
+                let hit = mesh.cast_triangle(part, ray)?;
+                Some(mesh_hit(mesh, &hit, ray))
+            }
+            _ => self.cast(ray),
+        }
+    }
+    /// Where `ray` first hits the shape: how far along it, the normal there
+    /// facing back along the ray, and whether that normal had to be turned
+    /// around to face it (from the inside of a sphere, or the back of a flat
+    /// shape). Planes only face one way, so they're never turned.
+    pub fn cast(&self, ray: Ray<f64>) -> Option<(f64, Vector3<f64>, bool)> {
         let dir = ray.dir;
         match self {
             Shape::Sphere { center, radius } => {
@@ -64,43 +270,567 @@ impl Shape {
                 if discr >= 0. {
                     let t = (-b - discr.sqrt()) / (2. * a);
                     if t > MIN_DIST {
-                        return Some((t, (ray.of(t) - center).normalize()));
+                        return Some((t, (ray.of(t) - center).normalize(), false));
                     }
                     let t = (-b + discr.sqrt()) / (2. * a);
                     if t > MIN_DIST {
                         // we are inside the sphere
-                        return Some((t, center - ray.of(t)));
+                        return Some((t, (center - ray.of(t)).normalize(), true));
                     }
                 }
             }
             Shape::Plane { center, normal } => {
                 let t = normal.dot(&(center - ray.start)) / normal.dot(&ray.dir);
                 if t > MIN_DIST {
-                    return Some((t, *normal));
+                    return Some((t, *normal, false));
                 }
             }
+            Shape::Triangle { vertices } => {
+                let (t, _) = intersect_triangle(ray, *vertices)?;
+                let normal = face_normal(*vertices);
+                // triangles are two sided, so face the normal towards the ray
+                if normal.dot(&dir) > 0. {
+                    return Some((t, -normal, true));
+                }
+                return Some((t, normal, false));
+            }
+            &Shape::Quad { corner, edges } => {
+                // the same two triangles as `triangles` gives, which share a
+                // normal
+                let [a, b] = edges;
+                let (t, _) = intersect_triangle(ray, [corner, corner + a, corner + a + b])
+                    .or_else(|| intersect_triangle(ray, [corner, corner + a + b, corner + b]))?;
+                let normal = a.cross(&b).normalize();
+                if normal.dot(&dir) > 0. {
+                    return Some((t, -normal, true));
+                }
+                return Some((t, normal, false));
+            }
+            Shape::Mesh(mesh) => {
+                let hit = mesh.cast(ray)?;
+                return Some(mesh_hit(mesh, &hit, ray));
+            }
         }
         None
     }
+    /// The triangles making up a flat shape, wound so that their normals
+    /// face the front of it
+    pub fn triangles(&self) -> Vec<[Vector3<f64>; 3]> {
+        match self {
+            Shape::Sphere { .. } | Shape::Plane { .. } => vec![],
+            Shape::Triangle { vertices } => vec![*vertices],
+            &Shape::Quad { corner, edges } => {
+                let [a, b] = edges;
+                vec![
+                    [corner, corner + a, corner + a + b],
+                    [corner, corner + a + b, corner + b],
+                ]
+            }
+            Shape::Mesh(mesh) => (0..mesh.triangle_count())
+                .map(|i| mesh.vertices(i))
+                .collect(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A hit on a mesh as `Shape::cast` gives it
+fn mesh_hit(mesh: &Mesh, hit: &MeshHit, ray: Ray<f64>) -> (f64, Vector3<f64>, bool) {
+    let flipped = face_normal(mesh.vertices(hit.triangle)).dot(&ray.dir) > 0.;
+    (hit.t, mesh.normal(hit, ray), flipped)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "LightDesc")]
 pub struct Light {
-    pub pos: Vector3<f64>,
+    /// The intensity of a point or spot light, the radiance of an area light,
+    /// the irradiance a directional light gives a surface facing it, or what
+    /// an environment map is scaled by
     pub color: Color,
+    pub emitter: Emitter,
+    /// How much faster than the inverse square law the light fades with
+    /// distance, as `1 / (1 + attenuation * d²)` along its first step. Zero,
+    /// for physically based light, unless a scene asks for it.
+    pub attenuation: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Emitter {
+    Point(Vector3<f64>),
+    /// A point light that only shines within a cone
+    Spot {
+        pos: Vector3<f64>,
+        /// Unit vector along the middle of the cone
+        facing: Vector3<f64>,
+        /// Cosine of the angle out to the edge of the cone
+        cos_outer: f64,
+        /// Cosine of the angle out to where the light starts to fade
+        cos_inner: f64,
+    },
+    /// Gives off light from the front of a surface: the outside of a sphere,
+    /// or the side of a flat shape that its normals face
+    Area(Surface),
+    /// Light from so far away that it all travels the same way, like the
+    /// sun's. It comes in through a disk just outside the scene, which
+    /// `Scene::new` fits around everything with bounds, and the part of each
+    /// infinite plane near the camera.
+    Directional {
+        /// Unit vector in the direction the light travels
+        dir: Vector3<f64>,
+        center: Vector3<f64>,
+        radius: f64,
+    },
+    /// Light from every direction, far away, coming in through a disk facing
+    /// the way it travels like a directional light's. Points on the disk go
+    /// with the direction of the light through them, which stands in for the
+    /// light's surface normal.
+    Environment {
+        map: Arc<Environment>,
+        center: Vector3<f64>,
+        radius: f64,
+    },
 }
 
+/// A point on a light, with the light's surface normal there (zero for point
+/// and spot lights, and the way the light travels for distant ones)
+pub type LightVertex<'a> = (Vector3<f64>, Vector3<f64>, &'a Light);
+
 impl Light {
-    pub fn propose<R: Rng + ?Sized>(&self, rng: &mut R) -> (f64, Vector3<f64>) {
-        let theta = rng.gen_range(0. ..TAU);
-        // Archimedes' hat-box theorem lets us generate a z-value and convert it to an angle
-        let z: f64 = rng.gen_range(-1. ..1.);
-        let r = (1. - z * z).sqrt();
-        (
-            1. / (4. * PI),
-            Vector3::new(theta.cos() * r, theta.sin() * r, z),
-        )
+    #[cfg(test)]
+    pub fn point(pos: Vector3<f64>, color: Color) -> Self {
+        Light {
+            color,
+            emitter: Emitter::Point(pos),
+            attenuation: 0.,
+        }
+    }
+    /// A light at `pos` shining towards `facing` in a cone `angle` radians
+    /// wide from the middle to the edge, fading out over the last `falloff`
+    /// radians of it
+    pub fn spot(
+        pos: Vector3<f64>,
+        facing: Vector3<f64>,
+        angle: f64,
+        falloff: f64,
+        color: Color,
+    ) -> Self {
+        let angle = angle.min(PI);
+        Light {
+            color,
+            emitter: Emitter::Spot {
+                pos,
+                facing: facing.normalize(),
+                cos_outer: angle.cos(),
+                cos_inner: (angle - falloff.min(angle)).cos(),
+            },
+            attenuation: 0.,
+        }
+    }
+    /// A light that gives off `color` as radiance from the front of `shape`,
+    /// or an error if the shape has no area or is infinite
+    pub fn area(shape: Shape, color: Color) -> Result<Self, String> {
+        Ok(Light {
+            color,
+            emitter: Emitter::Area(Surface::new(shape)?),
+            attenuation: 0.,
+        })
+    }
+    /// Light travelling along `dir` from far away, giving `color` to surfaces
+    /// facing it
+    pub fn directional(dir: Vector3<f64>, color: Color) -> Self {
+        Light {
+            color,
+            emitter: Emitter::Directional {
+                dir: dir.normalize(),
+                center: Vector3::zeros(),
+                radius: 0.,
+            },
+            attenuation: 0.,
+        }
+    }
+    /// Light from the environment map `map`, scaled by `intensity`
+    pub fn environment(map: Environment, intensity: f64) -> Self {
+        Light {
+            color: Color::new(intensity, intensity, intensity),
+            emitter: Emitter::Environment {
+                map: Arc::new(map),
+                center: Vector3::zeros(),
+                radius: 0.,
+            },
+            attenuation: 0.,
+        }
+    }
+    /// Pick a point on the light, uniformly by area, along with the surface
+    /// normal there. An environment map picks the direction first, by
+    /// importance.
+    pub fn sample_point<R: Rng + ?Sized>(&self, rng: &mut R) -> (Vector3<f64>, Vector3<f64>) {
+        match &self.emitter {
+            &Emitter::Point(pos) | &Emitter::Spot { pos, .. } => (pos, Vector3::zeros()),
+            Emitter::Area(surface) => surface.sample(rng),
+            Emitter::Directional { dir, .. } => self.sample_beam(*dir, rng),
+            Emitter::Environment { map, .. } => self.sample_beam(-map.sample(rng), rng),
+        }
     }
+    /// Pick a point on the disk light travelling along `dir` from a distant
+    /// light comes in through
+    fn sample_beam<R: Rng + ?Sized>(
+        &self,
+        dir: Vector3<f64>,
+        rng: &mut R,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let (center, radius) = self.beam().unwrap_or((Vector3::zeros(), 0.));
+        let r = radius * rng.gen::<f64>().sqrt();
+        let across = around(dir, 0., rng.gen_range(0. ..TAU));
+        (center - radius * dir + r * across, dir)
+    }
+    /// The center and radius of the disks a distant light's light comes in
+    /// through, or `None` for other lights
+    fn beam(&self) -> Option<(Vector3<f64>, f64)> {
+        match self.emitter {
+            Emitter::Directional { center, radius, .. }
+            | Emitter::Environment { center, radius, .. } => Some((center, radius)),
+            _ => None,
+        }
+    }
+    /// Pick a point on the light to light `point` from. That's any point
+    /// `sample_point` might pick, except for a distant light, whose light can
+    /// only come straight back along its direction, and which gives `None` if
+    /// that misses the disk it comes through.
+    pub fn sample_towards<R: Rng + ?Sized>(
+        &self,
+        point: Vector3<f64>,
+        rng: &mut R,
+    ) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        let dir = match &self.emitter {
+            &Emitter::Directional { dir, .. } => dir,
+            Emitter::Environment { map, .. } => -map.sample(rng),
+            _ => return Some(self.sample_point(rng)),
+        };
+        if !self.reaches(point, dir) {
+            return None;
+        }
+        Some((self.behind(point, dir), dir))
+    }
+    /// Whether light travelling along `dir` that a distant light sends in
+    /// through its disk can land on `point`: only if the point is in line
+    /// with the disk, and past it. Every integrator has to agree on that, or
+    /// they would light different parts of an infinite plane.
+    fn reaches(&self, point: Vector3<f64>, dir: Vector3<f64>) -> bool {
+        match self.beam() {
+            Some((center, radius)) => {
+                let offset = point - center;
+                let along = offset.dot(&dir);
+                along >= -radius && (offset - dir * along).norm() <= radius
+            }
+            None => true,
+        }
+    }
+    /// Where light from a distant light travelling along `dir` to `point`
+    /// came in, on the disk it comes through (or just `point`, for any other
+    /// light)
+    pub fn behind(&self, point: Vector3<f64>, dir: Vector3<f64>) -> Vector3<f64> {
+        match self.beam() {
+            Some((center, radius)) => point - dir * ((point - center).dot(&dir) + radius),
+            None => point,
+        }
+    }
+    /// Whether the light is infinitely far away. Its light doesn't spread out
+    /// with distance then, and the point it comes from is set by where it
+    /// lands.
+    pub fn is_distant(&self) -> bool {
+        self.beam().is_some()
+    }
+    /// How much of the light is left after travelling a squared distance
+    /// `d2` to the first thing it lands on, from the inverse square law's
+    /// point of view: all of it, unless the scene gave it an `attenuation`
+    pub fn attenuate(&self, d2: f64) -> f64 {
+        if self.is_distant() {
+            1.
+        } else {
+            1. / (1. + self.attenuation * d2)
+        }
+    }
+    /// Density per unit area of `sample_point` picking any given point, with
+    /// surface normal `normal`. A point or spot light always gives the same
+    /// one, which counts as 1, and so does a directional light, since the
+    /// point is set by where it lands. For an environment map it's the
+    /// density per unit solid angle of the direction.
+    pub fn point_pdf(&self, normal: Vector3<f64>) -> f64 {
+        match &self.emitter {
+            Emitter::Area(surface) => 1. / surface.area,
+            Emitter::Environment { map, .. } => map.pdf(-normal),
+            _ => 1.,
+        }
+    }
+    /// Pick a direction for light to leave the point with surface normal
+    /// `normal` in: any direction for a point light, any within the cone for
+    /// a spot light, cosine weighted around the normal for an area light, and
+    /// the only direction there is for a distant light
+    pub fn propose<R: Rng + ?Sized>(
+        &self,
+        normal: Vector3<f64>,
+        rng: &mut R,
+    ) -> (f64, Vector3<f64>) {
+        match self.emitter {
+            Emitter::Point(_) => {
+                let theta = rng.gen_range(0. ..TAU);
+                // Archimedes' hat-box theorem lets us generate a z-value and convert it to an angle
+                let z: f64 = rng.gen_range(-1. ..1.);
+                let r = (1. - z * z).sqrt();
+                (
+                    1. / (4. * PI),
+                    Vector3::new(theta.cos() * r, theta.sin() * r, z),
+                )
+            }
+            Emitter::Spot {
+                facing, cos_outer, ..
+            } => {
+                // the hat-box theorem again, over the cap the cone cuts out
+                let z = 1. - rng.gen::<f64>() * (1. - cos_outer);
+                (
+                    1. / (TAU * (1. - cos_outer)),
+                    around(facing, z, rng.gen_range(0. ..TAU)),
+                )
+            }
+            Emitter::Area(_) => {
+                let cos = rng.gen::<f64>().sqrt();
+                (cos / PI, around(normal, cos, rng.gen_range(0. ..TAU)))
+            }
+            Emitter::Directional { dir, .. } => (self.pdf(normal, dir), dir),
+            Emitter::Environment { .. } => (self.pdf(normal, normal), normal),
+        }
+    }
+    /// Density per unit solid angle of `propose` choosing a direction. A
+    /// distant light has only one direction, so for it this is the density
+    /// per unit area across the disk its light comes through.
+    pub fn pdf(&self, normal: Vector3<f64>, dir: Vector3<f64>) -> f64 {
+        match self.emitter {
+            Emitter::Point(_) => 1. / (4. * PI),
+            Emitter::Spot {
+                facing, cos_outer, ..
+            } => {
+                if facing.dot(&dir.normalize()) >= cos_outer {
+                    1. / (TAU * (1. - cos_outer))
+                } else {
+                    0.
+                }
+            }
+            Emitter::Area(_) => normal.dot(&dir.normalize()).max(0.) / PI,
+            Emitter::Directional { radius, .. } | Emitter::Environment { radius, .. } => {
+                1. / (PI * radius * radius)
+            }
+        }
+    }
+    /// For a distant light, the density per unit area of its light
+    /// travelling along `dir` landing at `point` on a surface with normal
+    /// `normal`, which is zero outside the disk it comes through. `None` for
+    /// any other light.
+    pub fn beam_pdf(
+        &self,
+        dir: Vector3<f64>,
+        point: Vector3<f64>,
+        normal: Vector3<f64>,
+    ) -> Option<f64> {
+        self.beam()?;
+        if !self.reaches(point, dir) {
+            return Some(0.);
+        }
+        Some(self.pdf(dir, dir) * dir.dot(&normal.normalize()).abs())
+    }
+    /// The light given off towards `dir` from the point with surface normal
+    /// `normal`, as `color` scaled by how much goes that way. For an area
+    /// light this is the cosine to the normal, which turns radiance into
+    /// light per unit area, for a spot light it fades smoothly to nothing
+    /// towards the edge of the cone, and an environment map gives its own
+    /// color for the direction the light comes from.
+    pub fn emitted(&self, normal: Vector3<f64>, dir: Vector3<f64>) -> Color {
+        let fraction = match &self.emitter {
+            Emitter::Point(_) | Emitter::Directional { .. } => 1.,
+            &Emitter::Spot {
+                facing,
+                cos_outer,
+                cos_inner,
+                ..
+            } => {
+                let cos = facing.dot(&dir.normalize());
+                if cos_inner <= cos_outer {
+                    if cos >= cos_outer {
+                        1.
+                    } else {
+                        0.
+                    }
+                } else {
+                    let t = ((cos - cos_outer) / (cos_inner - cos_outer)).clamp(0., 1.);
+                    t * t * (3. - 2. * t)
+                }
+            }
+            Emitter::Area(_) => normal.dot(&dir.normalize()).max(0.),
+            Emitter::Environment { map, .. } => return self.color * map.radiance(-dir),
+        };
+        self.color * fraction
+    }
+    /// Roughly how much light the light gives off in total, which is what
+    /// `Scene::sample_light` picks lights by. Spot lights count their whole
+    /// cone, fading edge and all, and distant lights what comes in through
+    /// their disk.
+    pub fn power(&self) -> f64 {
+        let spread = match &self.emitter {
+            Emitter::Point(_) => 4. * PI,
+            Emitter::Spot { cos_outer, .. } => TAU * (1. - cos_outer),
+            Emitter::Area(surface) => PI * surface.area,
+            Emitter::Directional { radius, .. } => PI * radius * radius,
+            Emitter::Environment { map, radius, .. } => PI * radius * radius * map.power(),
+        };
+        self.color.luminance() * spread
+    }
+}
+
+/// The shape of an area light, set up for picking points on it
+#[derive(Debug, Clone)]
+pub struct Surface {
+    pub shape: Shape,
+    pub area: f64,
+    /// The triangles of a flat shape, with the total area up to and including
+    /// each one, to pick them in proportion to their area
+    triangles: Vec<([Vector3<f64>; 3], f64)>,
+}
+
+impl Surface {
+    fn new(shape: Shape) -> Result<Self, String> {
+        let mut area = 0.;
+        let mut triangles = vec![];
+        match shape {
+            Shape::Sphere { radius, .. } => area = 4. * PI * radius * radius,
+            Shape::Plane { .. } => return Err("a light can't be an infinite plane".to_owned()),
+            _ => {
+                for [a, b, c] in shape.triangles() {
+                    area += (b - a).cross(&(c - a)).norm() / 2.;
+                    triangles.push(([a, b, c], area));
+                }
+            }
+        }
+        if area <= 0. {
+            return Err("a light's shape needs some area".to_owned());
+        }
+        Ok(Surface {
+            shape,
+            area,
+            triangles,
+        })
+    }
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> (Vector3<f64>, Vector3<f64>) {
+        if let &Shape::Sphere { center, radius } = &self.shape {
+            let normal = around(
+                Vector3::z(),
+                rng.gen_range(-1. ..1.),
+                rng.gen_range(0. ..TAU),
+            );
+            return (center + radius * normal, normal);
+        }
+        let target = rng.gen::<f64>() * self.area;
+        let i = self.triangles.partition_point(|&(_, total)| total < target);
+        let ([a, b, c], _) = self.triangles[i.min(self.triangles.len() - 1)];
+        // folding the unit square onto the triangle keeps the density uniform
+        let s = rng.gen::<f64>().sqrt();
+        let t = rng.gen::<f64>();
+        let point = a * (1. - s) + b * (s * (1. - t)) + c * (s * t);
+        (point, face_normal([a, b, c]))
+    }
+}
+
+/// How a light is written in a scene file: a `pos` for a point light, along
+/// with `facing` and `angle` (and optionally `falloff`) in degrees for a spot
+/// light, a `shape` for an area light, or a `direction` for a directional one.
+/// Any of them but a directional light can also have an `attenuation`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    pos: Option<Vector3<f64>>,
+    facing: Option<Vector3<f64>>,
+    angle: Option<f64>,
+    falloff: Option<f64>,
+    shape: Option<Shape>,
+    direction: Option<Vector3<f64>>,
+    color: Color,
+    attenuation: Option<f64>,
+}
+
+impl TryFrom<LightDesc> for Light {
+    type Error = String;
+    fn try_from(desc: LightDesc) -> Result<Self, String> {
+        let spot = (desc.facing, desc.angle, desc.falloff);
+        if desc.direction.is_some() && desc.attenuation.is_some() {
+            return Err("a directional light can't have an attenuation".to_owned());
+        }
+        let mut light = match (desc.pos, desc.shape, desc.direction, spot) {
+            (Some(pos), None, None, (None, None, None)) => Ok(Light {
+                color: desc.color,
+                emitter: Emitter::Point(pos),
+                attenuation: 0.,
+            }),
+            (Some(_), None, None, (Some(_), Some(angle), _)) if !(angle > 0. && angle < 180.) => {
+                Err("a spot light's angle has to be more than 0 and less than 180".to_owned())
+            }
+            (Some(_), None, None, (Some(_), Some(_), Some(falloff)))
+                if falloff < 0. || falloff.is_nan() =>
+            {
+                Err("a spot light's falloff can't be negative".to_owned())
+            }
+            (Some(pos), None, None, (Some(facing), Some(angle), falloff)) => Ok(Light::spot(
+                pos,
+                facing,
+                angle.to_radians(),
+                falloff.unwrap_or(0.).to_radians(),
+                desc.color,
+            )),
+            (Some(_), None, None, _) => {
+                Err("a spot light needs both a facing and an angle".to_owned())
+            }
+            (None, Some(shape), None, (None, None, None)) => Light::area(shape, desc.color),
+            (None, None, Some(dir), (None, None, None)) => Ok(Light::directional(dir, desc.color)),
+            _ => Err("a light needs either a pos, a shape or a direction".to_owned()),
+        }?;
+        light.attenuation = desc.attenuation.unwrap_or(0.);
+        Ok(light)
+    }
+}
+
+/// A camera looking down at a diffuse floor, with a wall made of `wall` to one
+/// side and a light between them, for checking that the integrators agree on
+/// light that bounces between surfaces
+#[cfg(test)]
+pub fn corner(wall: Material) -> Scene {
+    let light = Light::point(Vector3::new(0.5, 1., 0.25), Color::new(1., 1., 1.));
+    corner_lit(wall, light)
+}
+
+/// `corner`, but lit by `light`
+#[cfg(test)]
+pub fn corner_lit(wall: Material, light: Light) -> Scene {
+    Scene::new(
+        Camera::new(
+            Vector3::new(0., 2., 0.),
+            -Vector3::y(),
+            Vector3::z(),
+            PI / 4.,
+        ),
+        vec![
+            Object {
+                shape: Shape::Plane {
+                    center: Vector3::zeros(),
+                    normal: Vector3::y(),
+                },
+                material: Material::Diffuse(Color::new(0.5, 0.5, 0.5)),
+            },
+            Object {
+                shape: Shape::Plane {
+                    center: Vector3::x(),
+                    normal: -Vector3::x(),
+                },
+                material: wall,
+            },
+        ],
+        vec![light],
+    )
 }
 
 #[cfg(test)]
@@ -108,34 +838,62 @@ mod tests {
     use approx::assert_abs_diff_eq;
 
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn casting() {
-        let scene = Scene {
-            camera: Camera::new(
+        let scene = Scene::new(
+            Camera::new(
                 Vector3::new(0., 0., 2.),
                 -Vector3::z(),
                 Vector3::y(),
                 PI / 4.,
             ),
-            lights: vec![],
-            objects: vec![Object {
+            vec![Object {
                 shape: Shape::Sphere {
                     center: Vector3::new(0., 0., 0.),
                     radius: 1.,
                 },
                 material: Material::Diffuse(Color::new(1., 1., 1.)),
             }],
-        };
-        let rng = &mut rand::thread_rng();
-        for i in 0..100 {
-            let (_, dir) = scene.camera.propose(rng);
+            vec![],
+        );
+        let rng = &mut StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let dir = scene
+                .camera
+                .propose(rng.gen_range(-1. ..1.), rng.gen_range(-1. ..1.));
             let ray = Ray::new(scene.camera.pos, dir);
-            if let Some((t, _, _)) = scene.cast(ray) {
+            if let Some((t, ..)) = scene.cast(ray) {
                 let x = ray.of(t);
-                assert_abs_diff_eq!(x.norm(), 1.);
+                assert_abs_diff_eq!(x.norm(), 1., epsilon = 1e-9);
                 assert!(x[2] > 0.);
             }
         }
     }
+
+    #[test]
+    fn lights_are_picked_by_power() {
+        let dim = Light::point(Vector3::zeros(), Color::new(1., 1., 1.));
+        let bright = Light::point(Vector3::zeros(), Color::new(3., 3., 3.));
+        let mut scene = corner(Material::Diffuse(Color::new(1., 1., 1.)));
+        scene = Scene::new(scene.camera, scene.objects, vec![dim, bright]);
+        assert_abs_diff_eq!(
+            scene.light_pdf(&scene.lights[0], Vector3::zeros()),
+            0.25,
+            epsilon = 1e-9
+        );
+
+        let rng = &mut StdRng::seed_from_u64(1);
+        let picks = 10000;
+        let bright_picks = (0..picks)
+            .filter(|_| {
+                let (_, _, light) = scene.sample_light(rng).unwrap();
+                std::ptr::eq(light, &scene.lights[1])
+            })
+            .count();
+        let fraction = bright_picks as f64 / picks as f64;
+        assert!((fraction - 0.75).abs() < 0.02, "{}", fraction);
+    }
 }
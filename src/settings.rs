@@ -0,0 +1,259 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+/// Metropolis light transport renderer
+#[derive(Debug, StructOpt)]
+#[structopt(name = "metro")]
+pub struct Options {
+    /// Scene description file to render
+    #[structopt(parse(from_os_str), default_value = "scenes/default.toml")]
+    pub scene: PathBuf,
+    /// Save the image here once rendering finishes (or the window is closed).
+    /// The format is picked from the extension: .png and .ppm are tone mapped
+    /// to sRGB, while .hdr, .pfm and .exr keep the raw radiance
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub save: SaveOptions,
+    /// Render without opening a window, save the image to the output file and
+    /// exit. The exit status is nonzero if anything went wrong.
+    #[structopt(long, requires = "output")]
+    pub headless: bool,
+    /// Stop rendering after this many seconds
+    #[structopt(long, parse(try_from_str = seconds))]
+    pub time_limit: Option<f64>,
+    /// Stop rendering after this many samples have been recorded in total
+    #[structopt(long)]
+    pub sample_limit: Option<usize>,
+    #[structopt(flatten)]
+    pub render: RenderSettings,
+}
+
+// How images get written to disk. (Not a doc comment: structopt would show
+// those of flattened structs as the description of the whole program.)
+#[derive(Debug, Clone, Copy, Default, StructOpt)]
+pub struct SaveOptions {
+    /// Dither 8-bit images to hide banding
+    #[structopt(long)]
+    pub dither: bool,
+    /// Store OpenEXR images as 16-bit halfs rather than 32-bit floats
+    #[structopt(long)]
+    pub half: bool,
+    /// Brighten 8-bit images and the preview by this many stops (or darken
+    /// them, if negative). The HDR formats keep the raw radiance.
+    #[structopt(long, default_value = "0", parse(try_from_str = stops))]
+    pub exposure: f64,
+}
+
+// Everything the integrator needs to know besides the scene itself
+#[derive(Debug, Clone, Copy, StructOpt)]
+pub struct RenderSettings {
+    /// Image width in pixels
+    #[structopt(short, long, default_value = "640", parse(try_from_str = pixels))]
+    pub width: usize,
+    /// Image height in pixels
+    #[structopt(short = "H", long, default_value = "640", parse(try_from_str = pixels))]
+    pub height: usize,
+    /// Number of render threads
+    #[structopt(short = "j", long, default_value = "8")]
+    pub threads: usize,
+    /// How to render: `mlt` runs Markov chains that mutate paths directly,
+    /// `pssmlt` mutates the random numbers they are sampled from instead, and
+    /// `mmlt` does the same with separate chains for each path length. `erpt`
+    /// starts short chains from each pixel's own paths. `pt` is a plain path
+    /// tracer to check the others against, `bdpt` is a bidirectional path
+    /// tracer, `ppm` is progressive photon mapping, and `vcm` combines
+    /// bidirectional connections with photon mapping style merges.
+    #[structopt(
+        long,
+        default_value = "mlt",
+        possible_values = &["mlt", "pssmlt", "mmlt", "erpt", "pt", "bdpt", "ppm", "vcm"]
+    )]
+    pub integrator: Integrator,
+    /// Mutations per pixel (or for the path tracers and energy
+    /// redistribution, paths per pixel, and for photon mapping and vertex
+    /// merging, passes)
+    #[structopt(short = "n", long = "samples", default_value = "20")]
+    pub samples_per_pixel: usize,
+    /// Chance of adding another step to the traced path
+    #[structopt(long, default_value = "0.5", parse(try_from_str = chance))]
+    pub continue_chance: f64,
+    /// Chance of a primary sample space mutation throwing away every random
+    /// number, rather than nudging each of them a little
+    #[structopt(long, default_value = "0.3", parse(try_from_str = chance))]
+    pub large_step_probability: f64,
+    /// Independent paths sampled before rendering, to work out how bright the
+    /// image is and where to start the chains (for each path length, with
+    /// multiplexed MLT)
+    #[structopt(long, default_value = "100000")]
+    pub bootstrap_samples: usize,
+    /// Mutations in each energy redistribution chain
+    #[structopt(long, default_value = "100")]
+    pub chain_length: usize,
+    /// Photons traced in each photon mapping pass
+    #[structopt(long, default_value = "200000")]
+    pub photons: usize,
+    /// Distance photons are gathered from (or light points merged from) in the
+    /// first photon mapping or vertex merging pass. Later passes shrink it.
+    #[structopt(long, default_value = "0.05")]
+    pub photon_radius: f64,
+    /// Most surfaces a path bounces off with multiplexed MLT
+    #[structopt(long, default_value = "5")]
+    pub max_depth: usize,
+    /// Relative chance of a path mutation replacing part of the path with a
+    /// freshly sampled one
+    #[structopt(long, default_value = "1")]
+    pub bidirectional_weight: f64,
+    /// Relative chance of a path mutation nudging the point on the film, and
+    /// following the path through any glossy surfaces it sees
+    #[structopt(long, default_value = "1")]
+    pub lens_weight: f64,
+    /// Relative chance of a path mutation nudging the direction light leaves
+    /// the last diffuse surface before a chain of glossy ones (or the light)
+    #[structopt(long, default_value = "1")]
+    pub caustic_weight: f64,
+    /// Relative chance of a lens perturbation carrying on through diffuse
+    /// surfaces that are seen by way of glossy ones
+    #[structopt(long, default_value = "1")]
+    pub multi_chain_weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrator {
+    /// Veach-style mutations of the path itself
+    Mlt,
+    /// Kelemen-style mutations of the primary samples behind the path
+    Pssmlt,
+    /// Primary sample chains for each path length, which also pick how to
+    /// connect the path's light and camera ends
+    Mmlt,
+    /// Short path mutation chains, started from each pixel's own paths
+    Erpt,
+    /// Paths traced from the camera, with no Markov chains at all
+    PathTracer,
+    /// Subpaths traced from both ends and joined in every possible way
+    Bdpt,
+    /// Photons traced from the lights and gathered around camera paths
+    Ppm,
+    /// Bidirectional connections, along with merges between light and camera
+    /// points that land close together
+    Vcm,
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "mlt" => Ok(Integrator::Mlt),
+            "pssmlt" => Ok(Integrator::Pssmlt),
+            "mmlt" => Ok(Integrator::Mmlt),
+            "erpt" => Ok(Integrator::Erpt),
+            "pt" => Ok(Integrator::PathTracer),
+            "bdpt" => Ok(Integrator::Bdpt),
+            "ppm" => Ok(Integrator::Ppm),
+            "vcm" => Ok(Integrator::Vcm),
+            _ => Err(format!("unknown integrator: {}", s)),
+        }
+    }
+}
+
+/// Parse a probability, which has to be at least 0 and less than 1 (a path
+/// that is certain to go on would never end)
+fn chance(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0. ..1.).contains(&p) {
+        Ok(p)
+    } else {
+        Err(format!("{} is not at least 0 and less than 1", p))
+    }
+}
+
+/// Parse a size of the image, which can't be zero
+fn pixels(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|e| format!("{}", e))? {
+        0 => Err("the image needs at least one pixel each way".to_owned()),
+        n => Ok(n),
+    }
+}
+
+/// Parse a length of time, which can't be negative
+fn seconds(s: &str) -> Result<f64, String> {
+    let t: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if t >= 0. && t.is_finite() {
+        Ok(t)
+    } else {
+        Err(format!("{} is not a length of time", t))
+    }
+}
+
+/// Parse an exposure, which has to be a finite number of stops
+fn stops(s: &str) -> Result<f64, String> {
+    let stops: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if stops.is_finite() {
+        Ok(stops)
+    } else {
+        Err(format!("{} is not a number of stops", stops))
+    }
+}
+
+impl RenderSettings {
+    /// Height of the image as a fraction of its width
+    pub fn aspect(&self) -> f64 {
+        self.height as f64 / self.width as f64
+    }
+    /// Area of the film, which spans -1..1 across the width
+    pub fn film_area(&self) -> f64 {
+        4. * self.aspect()
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 640,
+            height: 640,
+            threads: 8,
+            integrator: Integrator::Mlt,
+            samples_per_pixel: 20,
+            continue_chance: 0.5,
+            large_step_probability: 0.3,
+            bootstrap_samples: 100_000,
+            chain_length: 100,
+            photons: 200_000,
+            photon_radius: 0.05,
+            max_depth: 5,
+            bidirectional_weight: 1.,
+            lens_weight: 1.,
+            caustic_weight: 1.,
+            multi_chain_weight: 1.,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::ErrorKind;
+
+    #[test]
+    fn parse_options() {
+        let options = Options::from_iter_safe(&["metro", "-w", "32", "-H", "16"]).unwrap();
+        assert_eq!((options.render.width, options.render.height), (32, 16));
+        let help = Options::from_iter_safe(&["metro", "-h"]).unwrap_err();
+        assert_eq!(help.kind, ErrorKind::HelpDisplayed);
+        assert!(help.message.contains("Metropolis light transport renderer"));
+        assert!(Options::from_iter_safe(&["metro", "--continue-chance", "1.5"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "--continue-chance", "NaN"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "--continue-chance", "1"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "--large-step-probability", "2"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "-w", "0"]).is_err());
+        let options = Options::from_iter_safe(&["metro", "--exposure=-2"]).unwrap();
+        assert_eq!(options.save.exposure, -2.);
+        assert!(Options::from_iter_safe(&["metro", "--exposure", "inf"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "-H", "0"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "--time-limit=-1"]).is_err());
+        assert!(Options::from_iter_safe(&["metro", "--time-limit", "NaN"]).is_err());
+    }
+}
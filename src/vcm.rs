@@ -0,0 +1,180 @@
+//! Vertex connection and merging (Georgiev et al. 2012). Each pass traces a
+//! light subpath for every pixel, then a camera subpath through every pixel,
+//! and joins them in every way bidirectional path tracing would. On top of
+//! that, each diffuse point on the camera subpath is merged with the points
+//! of every light subpath that land close to it, the way photon mapping
+//! gathers photons. Merging finds paths that no connection can, such as light
+//! focused by a glossy surface and then seen in one, and every way of finding
+//! a path is weighted against the others with the power heuristic. The merge
+//! radius shrinks from pass to pass as in `ppm`.
+
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use crate::bdpt::{roulette, subpath};
+use crate::camera::ImageBuffer;
+use crate::mlt::{Path, Vertex};
+use crate::ppm::{glossy, radius, PhotonMap};
+use crate::scene::{LightVertex, Scene};
+use crate::settings::RenderSettings;
+
+/// The ways a path can be sampled, numbered by how many of its surface points
+/// come from the light subpath
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Technique {
+    /// The light and camera subpaths are joined by a shadow ray
+    Connect(usize),
+    /// The last light point stands in for the last camera point near it
+    Merge(usize),
+}
+
+/// Run pass number `pass` (counting from 0), adding its share of the average
+/// over every pass to the image
+pub fn draw<R: Rng + ?Sized>(
+    pass: usize,
+    scene: &Scene,
+    image: &ImageBuffer,
+    settings: &RenderSettings,
+    rng: &mut R,
+) {
+    let camera = &scene.camera;
+    let c = settings.continue_chance;
+    let pixels = settings.width * settings.height;
+    let scale = 1. / (settings.film_area() * settings.samples_per_pixel as f64);
+
+    // (with no lights at all there aren't any)
+    let light_paths: Vec<(LightVertex, Vec<Vertex>)> = (0..pixels)
+        .filter_map(|_| {
+            let light = scene.sample_light(rng)?;
+            let (start, normal, emitter) = light;
+            let dir = emitter.propose(normal, rng).1;
+            Some((light, subpath(scene, start, dir, c, rng)))
+        })
+        .collect();
+    // each light point is stored as its subpath and how far along it it is
+    let light_points = light_paths
+        .iter()
+        .enumerate()
+        .flat_map(|(j, (_, vertices))| {
+            let points = vertices.iter().enumerate();
+            points.map(move |(s, &(point, ..))| (point, (j, s + 1)))
+        });
+    let grid = PhotonMap::new(light_points, radius(pass, settings));
+    // merging tries every light subpath against each camera point at once
+    let merge_area = PI * grid.radius.powi(2) * pixels as f64;
+
+    for (pixel, &(light, ref light_side)) in light_paths.iter().enumerate() {
+        let dir = camera.sample_pixel(pixel, settings, rng);
+        let camera_side = subpath(scene, camera.pos, dir, c, rng);
+
+        for s in 0..=light_side.len() {
+            for t in 0..=camera_side.len() {
+                if s + t == 0 {
+                    continue;
+                }
+                let path = Path::connect(light, &light_side[..s], &camera_side[..t], camera);
+                let density = path.split_pdf(0, path.last(), s, settings)
+                    * roulette(s, t, c)
+                    * scene.light_pdf(path.light, path.light_normal);
+                if density > 0. {
+                    let weight = mis_weight(&path, Technique::Connect(s), merge_area, settings);
+                    camera.record_sample(&path, scene, image, settings, weight * scale / density);
+                }
+            }
+        }
+
+        for t in 1..=camera_side.len() {
+            let (point, _, object) = camera_side[t - 1];
+            if glossy(object) {
+                continue;
+            }
+            for &(j, s) in grid.near(point) {
+                // the light point's subpath leads into the camera point
+                let (light, ref light_side) = light_paths[j];
+                let path = Path::connect(light, &light_side[..s - 1], &camera_side[..t], camera);
+                let density = path.merge_pdf(s, settings)
+                    * roulette(s, t, c)
+                    * merge_area
+                    * scene.light_pdf(path.light, path.light_normal);
+                if density > 0. {
+                    let weight = mis_weight(&path, Technique::Merge(s), merge_area, settings);
+                    camera.record_sample(&path, scene, image, settings, weight * scale / density);
+                }
+            }
+        }
+    }
+}
+
+/// Power heuristic weight of finding `path` with `technique`, against every
+/// connection and merge that could have found it. `merge_area` is the area
+/// merges gather from, times the number of light subpaths they gather over.
+fn mis_weight(
+    path: &Path,
+    technique: Technique,
+    merge_area: f64,
+    settings: &RenderSettings,
+) -> f64 {
+    let last = path.last();
+    let c = settings.continue_chance;
+    let pdf = |technique| match technique {
+        Technique::Connect(s) => {
+            (path.split_pdf(0, last, s, settings) * roulette(s, last - 1 - s, c)).powi(2)
+        }
+        Technique::Merge(s) if glossy(path.objects[s - 1]) => 0.,
+        Technique::Merge(s) => {
+            (path.merge_pdf(s, settings) * roulette(s, last - s, c) * merge_area).powi(2)
+        }
+    };
+    let connections = (0..last).map(Technique::Connect);
+    let merges = (1..last).map(Technique::Merge);
+    let total: f64 = connections.chain(merges).map(pdf).sum();
+    pdf(technique) / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::pathtracer;
+    use crate::scene::corner;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn matches_path_tracer() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let scene = corner(Material::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let settings = RenderSettings {
+            width: 16,
+            height: 16,
+            samples_per_pixel: 32,
+            // wide enough that merges carry a fair share of the light
+            photon_radius: 0.2,
+            ..Default::default()
+        };
+        let total = |image: &ImageBuffer| {
+            let total: Color = image.buffer.lock().unwrap().iter().copied().sum();
+            total.luminance()
+        };
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pass in 0..settings.samples_per_pixel {
+            draw(pass, &scene, &image, &settings, rng);
+        }
+        let vcm = total(&image);
+
+        let image = ImageBuffer::new(settings.width, settings.height);
+        for pixel in 0..settings.width * settings.height {
+            pathtracer::draw(pixel, 64, &scene, &image, &settings, rng);
+        }
+        let reference = total(&image);
+        assert!(
+            (vcm - reference).abs() < 0.05 * reference,
+            "{} vs {}",
+            vcm,
+            reference
+        );
+    }
+}
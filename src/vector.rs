@@ -1,4 +1,4 @@
-use nalgebra::{SimdRealField, Vector3};
+use nalgebra::{Rotation3, SimdRealField, Vector3};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Ray<T> {
@@ -16,3 +16,66 @@ impl<T> Ray<T> {
         self.start + self.dir * t
     }
 }
+
+/// A surface's local shading frame: a rotation taking its normal to +z, so
+/// directions can be worked with relative to the surface
+#[derive(Debug, Clone, Copy)]
+pub struct Frame(Rotation3<f64>);
+
+impl Frame {
+    pub fn new(normal: Vector3<f64>) -> Self {
+        // rotation_between gives up when the normal points straight down
+        Frame(
+            Rotation3::rotation_between(&Vector3::z(), &normal).unwrap_or_else(|| {
+                Rotation3::from_axis_angle(&Vector3::x_axis(), std::f64::consts::PI)
+            }),
+        )
+    }
+    pub fn to_local(self, v: Vector3<f64>) -> Vector3<f64> {
+        self.0.inverse_transform_vector(&v)
+    }
+    pub fn to_world(self, v: Vector3<f64>) -> Vector3<f64> {
+        self.0 * v
+    }
+}
+
+/// The unit vector at an angle with cosine `cos_theta` from `axis`, turned
+/// `phi` radians around it
+pub fn around(axis: Vector3<f64>, cos_theta: f64, phi: f64) -> Vector3<f64> {
+    Frame::new(axis).to_world(spherical(cos_theta, phi))
+}
+
+/// The unit vector at an angle with cosine `cos_theta` from +z, turned `phi`
+/// radians around it
+pub fn spherical(cos_theta: f64, phi: f64) -> Vector3<f64> {
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    Vector3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+/// The mirror reflection of `dir` about `normal`, both pointing away from the
+/// surface
+pub fn reflect(dir: Vector3<f64>, normal: Vector3<f64>) -> Vector3<f64> {
+    let normal = normal.normalize();
+    2. * dir.dot(&normal) * normal - dir
+}
+
+/// Where `dir` goes refracting through a surface with index of refraction
+/// `ior` behind it (and 1 in front of `normal`), both pointing away from the
+/// surface, or `None` if it would be totally internally reflected
+pub fn refract(dir: Vector3<f64>, normal: Vector3<f64>, ior: f64) -> Option<Vector3<f64>> {
+    let (dir, normal) = (dir.normalize(), normal.normalize());
+    let cos_in = dir.dot(&normal);
+    // eta is the ratio of the indices on the far side to the near side
+    let (eta, normal) = if cos_in > 0. {
+        (ior, normal)
+    } else {
+        (1. / ior, -normal)
+    };
+    let cos_in = cos_in.abs();
+    let sin2_out = (1. - cos_in * cos_in) / (eta * eta);
+    if sin2_out >= 1. {
+        return None;
+    }
+    let cos_out = (1. - sin2_out).sqrt();
+    Some(-dir / eta + normal * (cos_in / eta - cos_out))
+}